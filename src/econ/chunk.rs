@@ -0,0 +1,222 @@
+use crate::econ::command::{quote_into, unquote};
+use crate::format::formatting;
+use crate::model::CowStr;
+use serde::Deserialize;
+use serde_yaml::Value;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Splitting of chat commands longer than the game accepts.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChunkConfig {
+    /// Commands whose text argument is split
+    #[serde(default = "default_commands")]
+    pub commands: Vec<String>,
+    /// Bytes of text per command including the part number, 0 - no splitting
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    /// Prefix of every part, {{0}} - part, {{1}} - number of parts
+    #[serde(default = "default_number")]
+    pub number: CowStr<'static>,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            commands: default_commands(),
+            max_bytes: default_max_bytes(),
+            number: default_number(),
+        }
+    }
+}
+
+impl ChunkConfig {
+    /// `line` as one or more commands, each with at most `max_bytes` of text.
+    pub fn split(&self, line: String) -> Vec<String> {
+        let Some((command, rest)) = line.split_once(' ') else {
+            return vec![line];
+        };
+        if self.max_bytes == 0 || !self.commands.iter().any(|c| c == command) {
+            return vec![line];
+        }
+        // The text is either one quoted argument or the unquoted rest of the line
+        let text = match unquote(rest) {
+            Some(text) => text,
+            None if rest.contains(['"', ';']) => return vec![line],
+            None => rest.to_string(),
+        };
+        if text.len() <= self.max_bytes {
+            return vec![line];
+        }
+
+        let parts = self.split_text(&text);
+        let total = parts.len().to_string();
+        parts
+            .iter()
+            .enumerate()
+            .map(|(index, part)| {
+                let mut out = format!("{command} ");
+                let text = self.prefix(&(index + 1).to_string(), &total) + part.as_str();
+                quote_into(&mut out, &text);
+                out
+            })
+            .collect()
+    }
+
+    fn prefix(&self, part: &str, total: &str) -> String {
+        formatting::get_and_format(&self.number, &Value::Null, &[part, total]).into_owned()
+    }
+
+    /// Splits between words, words longer than a part between graphemes.
+    fn split_text(&self, text: &str) -> Vec<String> {
+        let mut total = text.len().div_ceil(self.max_bytes);
+        // More parts may need a longer prefix, which may need more parts
+        loop {
+            let digits = "9".repeat(total.to_string().len());
+            let budget = self
+                .max_bytes
+                .saturating_sub(self.prefix(&digits, &digits).len())
+                .max(1);
+            let parts = pack(text, budget);
+            if parts.len().to_string().len() <= digits.len() {
+                return parts;
+            }
+            total = parts.len();
+        }
+    }
+}
+
+fn pack(text: &str, budget: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    // Words keep their trailing whitespace, it separates them from the next one
+    for token in text.split_inclusive(char::is_whitespace) {
+        let word = token.trim_end();
+        if !current.is_empty() && current.len() + word.len() > budget {
+            push_part(&mut parts, &mut current);
+        }
+        if current.is_empty() && word.is_empty() {
+            continue;
+        }
+        if word.len() <= budget {
+            current.push_str(token);
+            continue;
+        }
+        for grapheme in token.graphemes(true) {
+            if !current.is_empty() && current.len() + grapheme.len() > budget {
+                push_part(&mut parts, &mut current);
+            }
+            // A part never starts with the whitespace ending a split word
+            if current.is_empty() && grapheme.trim().is_empty() {
+                continue;
+            }
+            current.push_str(grapheme);
+        }
+    }
+    push_part(&mut parts, &mut current);
+    parts
+}
+
+/// Moves `current` into `parts` without trailing whitespace, blank parts are dropped.
+fn push_part(parts: &mut Vec<String>, current: &mut String) {
+    let part = current.trim_end();
+    if !part.is_empty() {
+        parts.push(part.to_string());
+    }
+    current.clear();
+}
+
+fn default_commands() -> Vec<String> {
+    vec!["say".to_string()]
+}
+
+/// DDNet keeps 255 bytes of a chat message
+fn default_max_bytes() -> usize {
+    255
+}
+
+fn default_number() -> CowStr<'static> {
+    CowStr::Borrowed("({{0}}/{{1}}) ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_bytes: usize) -> ChunkConfig {
+        ChunkConfig {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_short_and_other_commands() {
+        let config = config(20);
+        assert_eq!(config.split(r#"say "hi""#.into()), [r#"say "hi""#]);
+        let long = format!("broadcast \"{}\"", "a".repeat(50));
+        assert_eq!(config.split(long.clone()), [long]);
+        let chained = format!("say {}; shutdown", "a".repeat(50));
+        assert_eq!(config.split(chained.clone()), [chained]);
+    }
+
+    #[test]
+    fn test_words_and_numbers() {
+        let parts = config(20).split(r#"say "one two three four five \"six\" seven""#.into());
+        assert_eq!(
+            parts,
+            [
+                r#"say "(1/3) one two three""#,
+                r#"say "(2/3) four five""#,
+                r#"say "(3/3) \"six\" seven""#,
+            ]
+        );
+        for part in parts {
+            assert!(unquote(&part[4..]).unwrap().len() <= 20);
+        }
+    }
+
+    #[test]
+    fn test_graphemes() {
+        // Family emoji is one grapheme of 25 bytes, never cut in the middle
+        let family = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+        let text = format!("{}{family}й", "ы".repeat(20));
+        let parts = config(40).split(format!("say {text}"));
+        let texts: Vec<String> = parts.iter().map(|p| unquote(&p[4..]).unwrap()).collect();
+        assert!(texts.iter().all(|t| t.len() <= 40));
+        assert!(texts
+            .iter()
+            .any(|t| t.ends_with(family) || t.contains(family)));
+        let joined: String = texts
+            .iter()
+            .map(|t| t.split_once(") ").unwrap().1)
+            .collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn test_many_parts() {
+        let parts = config(17).split(format!("say {}", "word ".repeat(30)));
+        assert_eq!(parts.len(), 15);
+        assert_eq!(parts[14], r#"say "(15/15) word word""#);
+        assert!(parts.iter().all(|p| unquote(&p[4..]).unwrap().len() <= 17));
+    }
+
+    #[test]
+    fn test_long_words_in_a_row() {
+        // The first word fills its last part exactly, its space must not become a part
+        assert_eq!(pack("aaaaaaaa bbbbbb", 4), ["aaaa", "aaaa", "bbbb", "bb"]);
+        assert_eq!(pack("aaaaaaaa cc", 4), ["aaaa", "aaaa", "cc"]);
+
+        let parts = config(20).split(format!("say {} {}", "a".repeat(28), "b".repeat(20)));
+        let texts: Vec<String> = parts.iter().map(|p| unquote(&p[4..]).unwrap()).collect();
+        assert_eq!(
+            texts,
+            [
+                format!("(1/4) {}", "a".repeat(14)),
+                format!("(2/4) {}", "a".repeat(14)),
+                format!("(3/4) {}", "b".repeat(14)),
+                format!("(4/4) {}", "b".repeat(6)),
+            ]
+        );
+    }
+}
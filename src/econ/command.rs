@@ -0,0 +1,238 @@
+use crate::format::formatting;
+use crate::util::hardcoded_regex;
+use anyhow::anyhow;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::fmt::Write;
+use std::sync::LazyLock;
+
+static NAME_RE: LazyLock<Regex> = LazyLock::new(|| hardcoded_regex(r"^[A-Za-z0-9_]+$"));
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandArg {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<&str> for CommandArg {
+    fn from(value: &str) -> Self {
+        CommandArg::Text(value.to_string())
+    }
+}
+
+impl From<String> for CommandArg {
+    fn from(value: String) -> Self {
+        CommandArg::Text(value)
+    }
+}
+
+impl From<i64> for CommandArg {
+    fn from(value: i64) -> Self {
+        CommandArg::Int(value)
+    }
+}
+
+/// Console command with typed arguments, serialised with DDNet console quoting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EconCommand {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<CommandArg>,
+}
+
+impl EconCommand {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<CommandArg>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Builds a single console line, text arguments can never break out of their quotes.
+    pub fn to_line(&self) -> anyhow::Result<String> {
+        if !NAME_RE.is_match(&self.command) {
+            return Err(anyhow!("Invalid econ command name \"{}\"", self.command));
+        }
+
+        let mut line = self.command.clone();
+        for arg in &self.args {
+            line.push(' ');
+            match arg {
+                CommandArg::Int(value) => write!(line, "{value}")?,
+                CommandArg::Float(value) if value.is_finite() => write!(line, "{value}")?,
+                CommandArg::Float(value) => {
+                    return Err(anyhow!("Invalid econ command argument {value}"));
+                }
+                CommandArg::Text(text) => quote_into(&mut line, text),
+            }
+        }
+        Ok(line)
+    }
+}
+
+/// Appends `text` as a quoted console argument.
+pub fn quote_into(out: &mut String, text: &str) {
+    out.reserve(text.len() + 2);
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            // a line break would start a new command
+            c if c.is_control() => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Drops everything that could end a statement or a quoted argument, for untrusted text
+/// placed into a raw console line.
+pub fn strip_console_syntax(text: &str) -> String {
+    text.chars()
+        .filter(|&c| !matches!(c, '"' | ';' | '\\' | '#') && !c.is_control())
+        .collect()
+}
+
+/// Reverses [`quote_into`], `None` unless `arg` is exactly one quoted argument.
+pub fn unquote(arg: &str) -> Option<String> {
+    let inner = arg.strip_prefix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next()? {
+                c @ ('\\' | '"') => out.push(c),
+                c => {
+                    out.push('\\');
+                    out.push(c);
+                }
+            },
+            '"' => return chars.next().is_none().then_some(out),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Command in configs: a raw console line or a structured [`EconCommand`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandTemplate {
+    Line(String),
+    Command(EconCommand),
+}
+
+impl CommandTemplate {
+    pub fn to_line(&self) -> anyhow::Result<String> {
+        match self {
+            CommandTemplate::Line(line) => Ok(line.clone()),
+            CommandTemplate::Command(command) => command.to_line(),
+        }
+    }
+
+    /// Fills `{{...}}` placeholders, structured arguments are quoted after the substitution.
+    pub fn render<T: AsRef<str>>(&self, args: &Value, list_values: &[T]) -> anyhow::Result<String> {
+        match self {
+            CommandTemplate::Line(line) => {
+                Ok(formatting::get_and_format(line, args, list_values).into_owned())
+            }
+            CommandTemplate::Command(command) => EconCommand {
+                command: command.command.clone(),
+                args: command
+                    .args
+                    .iter()
+                    .map(|arg| match arg {
+                        CommandArg::Text(text) => CommandArg::Text(
+                            formatting::get_and_format(text, args, list_values).into_owned(),
+                        ),
+                        other => other.clone(),
+                    })
+                    .collect(),
+            }
+            .to_line(),
+        }
+    }
+}
+
+impl From<String> for CommandTemplate {
+    fn from(value: String) -> Self {
+        CommandTemplate::Line(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::econ::policy::split_statements;
+
+    #[test]
+    fn test_simple() {
+        let line = EconCommand::new("kick")
+            .arg(3)
+            .arg("spam")
+            .to_line()
+            .unwrap();
+        assert_eq!(line, r#"kick 3 "spam""#);
+    }
+
+    #[test]
+    fn test_injection() {
+        let attempts = [
+            r#"hi"; shutdown; say ""#,
+            "hi\nshutdown",
+            "hi\r\nshutdown",
+            r#"hi\"; shutdown"#,
+            r#"hi\\"; shutdown; say "\"#,
+            "hi\0shutdown",
+        ];
+        for text in attempts {
+            let line = EconCommand::new("say").arg(text).to_line().unwrap();
+            assert_eq!(split_statements(&line).len(), 1, "{line}");
+            assert!(!line.contains(['\n', '\r', '\0']), "{line}");
+        }
+        assert_eq!(
+            EconCommand::new("say").arg(r#"a"b\c"#).to_line().unwrap(),
+            r#"say "a\"b\\c""#
+        );
+    }
+
+    #[test]
+    fn test_unquote() {
+        let mut line = String::new();
+        quote_into(&mut line, r#"a"b\c"#);
+        assert_eq!(unquote(&line).as_deref(), Some(r#"a"b\c"#));
+        assert_eq!(unquote("\"a\" b"), None);
+        assert_eq!(unquote("\"a"), None);
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        assert!(EconCommand::new("say; shutdown").to_line().is_err());
+        assert!(EconCommand::new("").to_line().is_err());
+        assert!(EconCommand::new("say")
+            .arg(CommandArg::Float(f64::NAN))
+            .to_line()
+            .is_err());
+    }
+
+    #[test]
+    fn test_template() {
+        let template: CommandTemplate =
+            serde_yaml::from_str("command: say\nargs: [\"Welcome {{0}}!\", 5]").unwrap();
+        let line = template.render(&Value::Null, &[r#"x"; shutdown"#]).unwrap();
+        assert_eq!(line, r#"say "Welcome x\"; shutdown!" 5"#);
+        assert_eq!(split_statements(&line).len(), 1);
+
+        let template: CommandTemplate = serde_yaml::from_str("\"bans_save bans\"").unwrap();
+        assert_eq!(template.to_line().unwrap(), "bans_save bans");
+    }
+}
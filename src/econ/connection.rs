@@ -0,0 +1,180 @@
+use crate::econ::model::EconConfig;
+use anyhow::anyhow;
+use serde_yaml::Value;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+const CONNECT_ATTEMPTS: u32 = 3;
+
+/// Upper bound for the password prompt and the reply to the password.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The only econ connection of a server, commands are written to the socket the output is read from.
+pub struct EconConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// Bytes of a line read so far, kept when a read is cancelled
+    partial: Vec<u8>,
+}
+
+impl EconConnection {
+    /// Connects and authenticates, connection errors are retried a few times.
+    pub async fn connect(config: &EconConfig, args: &Value) -> anyhow::Result<Self> {
+        let mut connection_error = None;
+        for attempt in 1..=CONNECT_ATTEMPTS {
+            match Self::open(config, args).await {
+                Ok(stream) => {
+                    let mut connection = Self::new(stream);
+                    connection.auth(config).await?;
+                    return Ok(connection);
+                }
+                Err(err) => connection_error = Some(anyhow!("Attempt {attempt}: {err}")),
+            }
+            if attempt < CONNECT_ATTEMPTS {
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+        Err(connection_error.unwrap_or_else(|| anyhow!("econ connect failed")))
+    }
+
+    async fn open(config: &EconConfig, args: &Value) -> anyhow::Result<TcpStream> {
+        let addr = config
+            .get_econ_addr(Some(args))
+            .map_err(|e| anyhow!("econ.get_econ_addr, err: {e}"))?;
+        TcpStream::connect(addr)
+            .await
+            .map_err(|e| anyhow!("econ connect to {addr} failed, err: {e}"))
+    }
+
+    /// Wraps a connected stream, authentication is left to the caller.
+    pub fn new(stream: TcpStream) -> Self {
+        let (read, writer) = stream.into_split();
+        Self {
+            reader: BufReader::new(read),
+            writer,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Answers the password prompt, lines after the reply stay buffered for [`Self::read_lines`].
+    async fn auth(&mut self, config: &EconConfig) -> anyhow::Result<()> {
+        timeout(AUTH_TIMEOUT, self.read_line())
+            .await
+            .map_err(|_| anyhow!("econ sent no password prompt"))??;
+        self.send_line(&config.password).await?;
+        let reply = timeout(AUTH_TIMEOUT, self.read_line())
+            .await
+            .map_err(|_| anyhow!("econ didn't answer the password"))??;
+        if reply.starts_with(&config.auth_message) {
+            Ok(())
+        } else {
+            Err(anyhow!("Econ client is not authorized: {reply}"))
+        }
+    }
+
+    pub async fn send_line(&mut self, line: &str) -> std::io::Result<()> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.writer.write_all(&bytes).await
+    }
+
+    /// Waits for the next lines, fails once the connection is lost.
+    ///
+    /// Cancel safe, a partly read line is completed by the next call.
+    pub async fn read_lines(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut lines = vec![self.read_line().await?];
+        // Lines already buffered come with the first one
+        while self.reader.buffer().contains(&b'\n') {
+            lines.push(self.read_line().await?);
+        }
+        Ok(lines)
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let read = self
+            .reader
+            .read_until(b'\n', &mut self.partial)
+            .await
+            .map_err(|e| anyhow!("econ read failed: {e}"))?;
+        if read == 0 || self.partial.last() != Some(&b'\n') {
+            return Err(anyhow!("econ closed the connection"));
+        }
+        let line = String::from_utf8_lossy(&self.partial)
+            .trim_end_matches(['\n', '\r'])
+            .replace('\0', "");
+        self.partial.clear();
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn server(
+        script: &'static [&'static [u8]],
+    ) -> (EconConfig, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut received = Vec::new();
+            write.write_all(b"Enter password:\n").await.unwrap();
+            for chunk in script {
+                if chunk.is_empty() {
+                    received.push(lines.next_line().await.unwrap().unwrap());
+                } else {
+                    write.write_all(chunk).await.unwrap();
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }
+            received
+        });
+        let config =
+            serde_yaml::from_str(&format!("{{host: '{addr}', password: secret}}")).unwrap();
+        (config, server)
+    }
+
+    #[tokio::test]
+    async fn test_read_write_and_close() {
+        // An empty chunk waits for a line from the client
+        let (config, server) = server(&[
+            b"",
+            b"Authentication successful.\none\n",
+            b"tw",
+            b"o\xff\r\n",
+            b"",
+            b"got it\n",
+        ])
+        .await;
+        let mut connection = EconConnection::connect(&config, &Value::Null)
+            .await
+            .unwrap();
+        let mut lines = Vec::new();
+        while lines.len() < 2 {
+            lines.extend(connection.read_lines().await.unwrap());
+        }
+        assert_eq!(lines, ["one", "two\u{FFFD}"]);
+
+        connection.send_line("status").await.unwrap();
+        assert_eq!(connection.read_lines().await.unwrap(), ["got it"]);
+        assert_eq!(server.await.unwrap(), ["secret", "status"]);
+        assert!(connection.read_lines().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password() {
+        let (config, server) = server(&[b"", b"Wrong password 1/3.\n"]).await;
+        assert!(EconConnection::connect(&config, &Value::Null)
+            .await
+            .is_err());
+        server.await.unwrap();
+    }
+}
@@ -1,16 +1,103 @@
-use crate::econ::model::LineState;
-use futures_util::future::join_all;
-use log::{debug, warn};
-use rand::prelude::SliceRandom;
+use crate::args::Args;
+use crate::econ::command::{strip_console_syntax, CommandTemplate};
+use crate::econ::events::EconEvent;
+use crate::econ::model::{EconMessage, TASK_SOURCE};
+use crate::econ::profile::LogProfile;
+use crate::econ::roster::{PlayerCondition, Roster};
+use crate::econ::rotation::{Rotation, RotationState};
+use crate::econ::timing::{config_seconds, format_duration, parse_duration, Timing, TimingConfig};
+use crate::util::captures_to_list;
+use log::{debug, info, warn};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use serde_yaml::{Mapping, Value};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
 use tokio::time::sleep;
 
+/// Everything a running task needs from its server.
+#[derive(Clone)]
+pub struct TaskContext {
+    pub tx: Sender<EconMessage>,
+    pub args: Value,
+    pub profile: LogProfile,
+    /// Every line read from econ
+    pub lines: broadcast::Sender<String>,
+    pub roster: Roster,
+}
+
+/// Shared view of a running task for the control subject.
+#[derive(Default, Debug)]
+pub struct TaskStatus {
+    /// Unix time in seconds, 0 - not scheduled
+    next_fire: AtomicI64,
+}
+
+impl TaskStatus {
+    pub fn next_fire(&self) -> Option<i64> {
+        Some(self.next_fire.load(Ordering::Relaxed)).filter(|&time| time != 0)
+    }
+
+    fn set_next_fire(&self, time: Option<i64>) {
+        self.next_fire.store(time.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+/// Condition for event tasks, every field that is set has to match.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct EventTrigger {
+    /// Typed event kind: chat, join, leave, kill, finish, map, ...
+    #[serde(default)]
+    pub event: Option<String>,
+    /// Regex matched against the whole econ line
+    #[serde(default)]
+    pub regex: Option<String>,
+}
+
+impl EventTrigger {
+    /// Values for the command templates: event fields and named groups as `{{event.name}}`,
+    /// numbered groups as `{{0}}`, `{{1}}`, ...
+    fn captures(
+        &self,
+        line: &str,
+        regex: Option<&Regex>,
+        profile: LogProfile,
+    ) -> Option<(Value, Vec<String>)> {
+        let mut values = Mapping::new();
+        if let Some(kind) = &self.event {
+            let event = profile.parse(line).and_then(|log| EconEvent::parse(&log))?;
+            if event.kind() != kind {
+                return None;
+            }
+            if let Ok(Value::Mapping(fields)) = serde_yaml::to_value(&event) {
+                values = fields;
+            }
+        }
+
+        let mut list = Vec::new();
+        if let Some(re) = regex {
+            let caps = re.captures(line)?;
+            list = captures_to_list(&caps)
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+            for name in re.capture_names().flatten() {
+                if let Some(value) = caps.name(name) {
+                    values.insert(name.into(), value.as_str().into());
+                }
+            }
+        }
+        Some((Value::Mapping(values), list))
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum TaskType {
@@ -18,59 +105,248 @@ pub enum TaskType {
     Line,
     Random,
     All,
+    /// Random by the weights of the messages file, 1 for every inline command
+    Weighted,
+    /// Every command once in random order before any repeats
+    Shuffle,
 }
 
+/// The variant is chosen by its key: `cron`, `countdown`, `on`, otherwise a delay task.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, remote = "Self")]
 pub enum Task {
     Cron {
         cron: String,
-        commands: Vec<String>,
+        commands: Vec<CommandTemplate>,
         #[serde(default)]
         r#type: TaskType,
+        #[serde(flatten)]
+        messages: MessagesConfig,
+        #[serde(flatten)]
+        timing: TimingConfig,
+        #[serde(flatten)]
+        players: PlayerCondition,
         #[serde(skip)]
-        #[serde(default = "default_line_state")]
-        state: LineState,
+        state: RotationState,
+    },
+    Countdown {
+        countdown: CountdownConfig,
+        /// Sent at every offset, `{{remaining}}` - time left, `{{seconds}}` - the same in seconds
+        #[serde(default)]
+        warning: Vec<CommandTemplate>,
+        /// Sent when the countdown reaches zero
+        commands: Vec<CommandTemplate>,
+        /// IANA name for `cron` and `at`, the host local time if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timezone: Option<String>,
+    },
+    Event {
+        on: EventTrigger,
+        commands: Vec<CommandTemplate>,
+        #[serde(flatten)]
+        players: PlayerCondition,
     },
     Delay {
-        commands: Vec<String>,
+        commands: Vec<CommandTemplate>,
         #[serde(default = "default_tasks_delay_sec")]
         delay: u64,
+        #[serde(default = "default_delay_type")]
+        r#type: TaskType,
+        #[serde(flatten)]
+        messages: MessagesConfig,
+        #[serde(flatten)]
+        timing: TimingConfig,
+        #[serde(flatten)]
+        players: PlayerCondition,
+        #[serde(skip)]
+        state: RotationState,
     },
 }
 
+impl Serialize for Task {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Task::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Task {
+    /// A task with the key of a variant that doesn't parse is an error, never another variant.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value = Value::deserialize(deserializer)?;
+        let field = |key: &str| value.as_mapping().and_then(|m| m.get(key)).cloned();
+        let (kind, detail) = if let Some(countdown) = field("countdown") {
+            let detail = serde_yaml::from_value::<CountdownConfig>(countdown).err();
+            ("countdown", detail)
+        } else if let Some(on) = field("on") {
+            ("event", serde_yaml::from_value::<EventTrigger>(on).err())
+        } else if let Some(cron) = field("cron") {
+            ("cron", serde_yaml::from_value::<String>(cron).err())
+        } else {
+            ("delay", None)
+        };
+        if let Some(err) = detail {
+            return Err(D::Error::custom(format!("invalid {kind} task: {err}")));
+        }
+        match Task::deserialize(value) {
+            Ok(task) if task.kind() == kind => Ok(task),
+            Ok(_) => Err(D::Error::custom(format!("invalid {kind} task"))),
+            Err(err) => Err(D::Error::custom(format!("invalid {kind} task: {err}"))),
+        }
+    }
+}
+
+/// Target of a countdown task, either `cron` or `at`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CountdownConfig {
+    /// Repeating target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    /// One time target like `2025-06-14 18:00`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<String>,
+    /// Time before the target to send `warning` at: `10m`, `1h30m`, `10s`
+    #[serde(default = "default_countdown_offsets")]
+    pub offsets: Vec<String>,
+}
+
+/// Commands kept outside of the config for cron and delay tasks.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MessagesConfig {
+    /// Text file with one command per line or a YAML list, replaces `commands`
+    /// and is read again when it changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// JSON file keeping the rotation position across restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position_file: Option<String>,
+}
+
+/// Task from the config, `name` identifies it on the control subject.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamedTask {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Start without running until resumed
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(flatten)]
+    pub task: Task,
+}
+
 impl Default for Task {
     fn default() -> Self {
         Task::Delay {
             delay: 5,
-            commands: vec![String::new()],
+            r#type: default_delay_type(),
+            messages: MessagesConfig::default(),
+            state: RotationState::default(),
+            commands: vec![CommandTemplate::Line(String::new())],
+            timing: TimingConfig::default(),
+            players: PlayerCondition::default(),
         }
     }
 }
 impl Task {
-    pub fn get_all_commands(&self) -> HashSet<String> {
+    pub fn init_state(&mut self) {
+        if let Task::Cron {
+            commands,
+            r#type,
+            messages,
+            state,
+            ..
+        }
+        | Task::Delay {
+            commands,
+            r#type,
+            messages,
+            state,
+            ..
+        } = self
+        {
+            *state = Arc::new(Mutex::new(Rotation::new(
+                r#type.clone(),
+                Self::lines(commands),
+                messages.file.as_ref().map(PathBuf::from),
+                messages.position_file.as_ref().map(PathBuf::from),
+            )));
+        }
+    }
+
+    fn lines(commands: &[CommandTemplate]) -> Vec<String> {
+        commands
+            .iter()
+            .filter_map(|command| match command.to_line() {
+                Ok(line) => Some(line),
+                Err(e) => {
+                    warn!("Skipping task command: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Checks timing values that would only fail once the task runs.
+    pub fn validate(&self) -> anyhow::Result<()> {
         match self {
-            Task::Cron { commands, .. } | Task::Delay { commands, .. } => {
-                commands.iter().cloned().collect()
+            Task::Cron { timing, .. } => Timing::new(timing).map(|_| ()),
+            Task::Delay { delay, timing, .. } => {
+                config_seconds(*delay, "delay")?;
+                Timing::new(timing).map(|_| ())
+            }
+            Task::Countdown { countdown, .. } => {
+                match countdown
+                    .offsets
+                    .iter()
+                    .find(|o| parse_duration(o).is_none())
+                {
+                    Some(offset) => Err(anyhow::anyhow!("Invalid countdown offset '{offset}'")),
+                    None => Ok(()),
+                }
             }
+            Task::Event { .. } => Ok(()),
         }
     }
 
-    pub fn init_state(&mut self) {
-        if let Task::Cron {
-            commands, state, ..
-        } = self
-        {
-            *state = LineState::new(commands.clone());
+    /// Inline commands of the task, messages files are not included.
+    pub fn templates(&self) -> impl Iterator<Item = &CommandTemplate> {
+        let (commands, warning) = match self {
+            Task::Countdown {
+                warning, commands, ..
+            } => (commands, &warning[..]),
+            Task::Cron { commands, .. }
+            | Task::Event { commands, .. }
+            | Task::Delay { commands, .. } => (commands, &[][..]),
+        };
+        warning.iter().chain(commands)
+    }
+
+    /// Messages file of cron and delay tasks.
+    pub fn messages_file(&self) -> Option<&str> {
+        match self {
+            Task::Cron { messages, .. } | Task::Delay { messages, .. } => messages.file.as_deref(),
+            Task::Countdown { .. } | Task::Event { .. } => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Task::Cron { .. } => "cron",
+            Task::Countdown { .. } => "countdown",
+            Task::Event { .. } => "event",
+            Task::Delay { .. } => "delay",
         }
     }
 
-    pub async fn execute(&self, tx: &Sender<String>) {
+    pub async fn execute(&self, ctx: &TaskContext, status: &TaskStatus) {
+        let tx = &ctx.tx;
         match self {
             Task::Cron {
                 cron,
                 state,
-                r#type,
+                timing,
+                players,
                 ..
             } => {
                 let schedule = match cron::Schedule::from_str(cron) {
@@ -80,63 +356,397 @@ impl Task {
                         return;
                     }
                 };
+                let Some(timing) = Self::timing(timing) else {
+                    return;
+                };
+                let mut after = timing.first_run(chrono::Utc::now());
+                loop {
+                    let Some(next) = timing.next_cron(&schedule, after) else {
+                        status.set_next_fire(None);
+                        info!("Cron task '{cron}' has no runs left in its window");
+                        return;
+                    };
+                    Self::sleep_until(status, next + timing.jitter()).await;
+                    if Self::players_match(ctx, players) {
+                        Self::process_commands(tx, state).await;
+                    }
+                    after = next.max(chrono::Utc::now());
+                }
+            }
+            Task::Countdown {
+                countdown,
+                warning,
+                commands,
+                timezone,
+            } => Self::countdown(ctx, status, countdown, warning, commands, timezone).await,
+            Task::Event {
+                on,
+                commands,
+                players,
+            } => Self::on_event(ctx, on, commands, players).await,
+            Task::Delay {
+                delay,
+                timing,
+                players,
+                state,
+                ..
+            } => {
+                let Some(timing) = Self::timing(timing) else {
+                    return;
+                };
+                let delay = match config_seconds(*delay, "delay") {
+                    Ok(delay) => delay,
+                    Err(e) => {
+                        warn!("Invalid delay task: {e}");
+                        return;
+                    }
+                };
+                let mut at = timing.first_run(chrono::Utc::now());
                 loop {
-                    if let Some(next) = schedule.upcoming(chrono::Local).next() {
-                        let duration = (next - chrono::Local::now())
-                            .to_std()
-                            .unwrap_or(Duration::ZERO);
-                        sleep(duration).await;
-                        Self::process_commands(tx, state, r#type).await;
+                    let Some(next) = timing.next_run(at) else {
+                        status.set_next_fire(None);
+                        info!("Delay task has no runs left in its window");
+                        return;
+                    };
+                    Self::sleep_until(status, next + timing.jitter()).await;
+                    if Self::players_match(ctx, players) {
+                        Self::process_commands(tx, state).await;
                     }
+                    at = chrono::Utc::now() + delay;
                 }
             }
-            Task::Delay { delay, commands } => loop {
-                for command in commands {
-                    Self::send_command(tx, command).await;
+        }
+    }
+
+    fn players_match(ctx: &TaskContext, condition: &PlayerCondition) -> bool {
+        let players = ctx.roster.count();
+        let matches = condition.matches(players);
+        if !matches {
+            debug!("tasks: skipped, {players} players on the server");
+        }
+        matches
+    }
+
+    fn timing(config: &TimingConfig) -> Option<Timing> {
+        Timing::new(config)
+            .inspect_err(|e| warn!("Invalid task timing: {e}"))
+            .ok()
+    }
+
+    async fn sleep_until(status: &TaskStatus, time: chrono::DateTime<chrono::Utc>) {
+        status.set_next_fire(Some(time.timestamp()));
+        sleep(
+            (time - chrono::Utc::now())
+                .to_std()
+                .unwrap_or(Duration::ZERO),
+        )
+        .await;
+    }
+
+    /// Runs the commands of the task once, outside of its schedule.
+    pub async fn run_once(&self, ctx: &TaskContext) {
+        match self {
+            Task::Cron { state, .. } | Task::Delay { state, .. } => {
+                Self::process_commands(&ctx.tx, state).await;
+            }
+            Task::Countdown { commands, .. } | Task::Event { commands, .. } => {
+                Self::render_and_send(ctx, commands, &ctx.args, &[] as &[&str]).await;
+            }
+        }
+    }
+
+    async fn countdown(
+        ctx: &TaskContext,
+        status: &TaskStatus,
+        config: &CountdownConfig,
+        warning: &[CommandTemplate],
+        commands: &[CommandTemplate],
+        timezone: &Option<String>,
+    ) {
+        let timing = TimingConfig {
+            timezone: timezone.clone(),
+            ..Default::default()
+        };
+        let Some(timing) = Self::timing(&timing) else {
+            return;
+        };
+        let schedule = match config
+            .cron
+            .as_deref()
+            .map(cron::Schedule::from_str)
+            .transpose()
+        {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                warn!("Invalid countdown cron expression: {e}");
+                return;
+            }
+        };
+        let at = match config
+            .at
+            .as_deref()
+            .map(|at| timing.parse_time(at))
+            .transpose()
+        {
+            Ok(at) => at,
+            Err(e) => {
+                warn!("Invalid countdown time: {e}");
+                return;
+            }
+        };
+        if schedule.is_none() == at.is_none() {
+            warn!("Countdown task needs exactly one of `cron` and `at`");
+            return;
+        }
+        let mut offsets: Vec<chrono::TimeDelta> = config
+            .offsets
+            .iter()
+            .filter_map(|offset| {
+                let parsed = parse_duration(offset);
+                if parsed.is_none() {
+                    warn!("Skipping invalid countdown offset '{offset}'");
                 }
-                sleep(Duration::from_secs(*delay)).await;
-            },
+                parsed
+            })
+            .collect();
+        offsets.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut after = chrono::Utc::now();
+        loop {
+            let target = match (&schedule, at) {
+                (Some(schedule), _) => timing.next_cron(schedule, after),
+                (None, at) => at.filter(|&at| at > after),
+            };
+            let Some(target) = target else {
+                status.set_next_fire(None);
+                info!("Countdown task has no target left");
+                return;
+            };
+
+            for &offset in &offsets {
+                let warn_at = target - offset;
+                if warn_at < chrono::Utc::now() {
+                    continue;
+                }
+                Self::sleep_until(status, warn_at).await;
+                let mut values = Mapping::new();
+                values.insert("remaining".into(), format_duration(offset).into());
+                values.insert("seconds".into(), offset.num_seconds().into());
+                let args = Args::merge_yaml_values(&ctx.args, &Value::Mapping(values));
+                Self::render_and_send(ctx, warning, &args, &[] as &[&str]).await;
+            }
+            Self::sleep_until(status, target).await;
+            Self::render_and_send(ctx, commands, &ctx.args, &[] as &[&str]).await;
+            after = target;
         }
     }
 
-    async fn process_commands(tx: &Sender<String>, state: &LineState, exec_type: &TaskType) {
-        match exec_type {
-            TaskType::Line => {
-                let cmd = state.get_next_command();
-                Self::send_command(tx, &cmd).await;
+    async fn render_and_send<T: AsRef<str>>(
+        ctx: &TaskContext,
+        commands: &[CommandTemplate],
+        args: &Value,
+        list: &[T],
+    ) {
+        for command in commands {
+            match command.render(args, list) {
+                Ok(command) => Self::send_command(&ctx.tx, &command).await,
+                Err(e) => warn!("Skipping task command: {e}"),
             }
-            TaskType::Random => {
-                let chosen = {
-                    let mut rng = rand::thread_rng();
-                    state.commands.choose(&mut rng).map(String::as_str)
-                };
+        }
+    }
 
-                match chosen {
-                    None => warn!("random: No commands available"),
-                    Some(result) => Self::send_command(tx, result).await,
+    async fn on_event(
+        ctx: &TaskContext,
+        on: &EventTrigger,
+        commands: &[CommandTemplate],
+        players: &PlayerCondition,
+    ) {
+        if on.event.is_none() && on.regex.is_none() {
+            warn!("Event task without `event` or `regex` never fires");
+            return;
+        }
+        let regex = match on.regex.as_deref().map(Regex::new).transpose() {
+            Ok(regex) => regex,
+            Err(e) => {
+                warn!("Invalid event task regex: {e}");
+                return;
+            }
+        };
+
+        let mut lines = ctx.lines.subscribe();
+        loop {
+            let line = match lines.recv().await {
+                Ok(line) => line,
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Event task lagged, {count} lines skipped");
+                    continue;
                 }
+                Err(RecvError::Closed) => return,
+            };
+            let Some((captures, list)) = on.captures(&line, regex.as_ref(), ctx.profile) else {
+                continue;
+            };
+            if !Self::players_match(ctx, players) {
+                continue;
             }
-            TaskType::All => {
-                join_all(state.commands.iter().map(|cmd| Self::send_command(tx, cmd))).await;
+            for command in commands {
+                match render_event(command, &ctx.args, &captures, &list) {
+                    Ok(command) => Self::send_command(&ctx.tx, &command).await,
+                    Err(e) => warn!("Skipping task command: {e}"),
+                }
             }
         }
     }
 
-    async fn send_command(tx: &Sender<String>, command: &str) {
+    async fn process_commands(tx: &Sender<EconMessage>, state: &RotationState) {
+        let commands = state.lock().await.next().await;
+        for command in commands {
+            Self::send_command(tx, &command).await;
+        }
+    }
+
+    async fn send_command(tx: &Sender<EconMessage>, command: &str) {
         debug!("tasks: send message to econ, msg: \"{command}\"");
-        if let Err(e) = tx.send(command.to_string()).await {
+        let message = EconMessage {
+            command: command.to_string(),
+            source: TASK_SOURCE.to_string(),
+        };
+        if let Err(e) = tx.send(message).await {
             warn!("tx.send error, task failed: {e}");
         }
     }
 }
 
-fn default_line_state() -> LineState {
-    LineState {
-        index: Arc::new(AtomicUsize::new(0)),
-        commands: Vec::new(),
+/// Renders an event task command, the captures are available as `{{event.*}}` and `{{0}}`, ...
+///
+/// Captures are player controlled: structured arguments are quoted, raw lines only get them
+/// stripped of console syntax.
+fn render_event(
+    command: &CommandTemplate,
+    args: &Value,
+    captures: &Value,
+    list: &[String],
+) -> anyhow::Result<String> {
+    let with_event = |captures: Value| {
+        let mut values = Mapping::new();
+        values.insert("event".into(), captures);
+        Args::merge_yaml_values(args, &Value::Mapping(values))
+    };
+    match command {
+        CommandTemplate::Line(_) => {
+            let captures = match captures {
+                Value::Mapping(fields) => fields
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Value::String(text) => (key.clone(), strip_console_syntax(text).into()),
+                        value => (key.clone(), value.clone()),
+                    })
+                    .collect(),
+                _ => Mapping::new(),
+            };
+            let list: Vec<String> = list.iter().map(|s| strip_console_syntax(s)).collect();
+            command.render(&with_event(Value::Mapping(captures)), &list)
+        }
+        CommandTemplate::Command(_) => command.render(&with_event(captures.clone()), list),
     }
 }
 
+fn default_countdown_offsets() -> Vec<String> {
+    ["10m", "5m", "1m", "10s"].map(String::from).to_vec()
+}
+
+fn default_delay_type() -> TaskType {
+    TaskType::All
+}
+
 fn default_tasks_delay_sec() -> u64 {
     60
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_captures() {
+        let trigger = EventTrigger {
+            event: Some("join".to_string()),
+            regex: None,
+        };
+        let line = "2024-01-01 12:00:00 I game: team_join player='3:nameless tee' team=0";
+        let (values, _) = trigger.captures(line, None, LogProfile::Ddnet).unwrap();
+        assert_eq!(Args::get(&values, "name", String::new()), "nameless tee");
+        assert!(trigger
+            .captures(
+                line.replace("team_join", "leave").as_str(),
+                None,
+                LogProfile::Ddnet
+            )
+            .is_none());
+
+        let trigger = EventTrigger {
+            event: None,
+            regex: Some(r"map (?<map>\S+) loaded".to_string()),
+        };
+        let regex = Regex::new(trigger.regex.as_deref().unwrap()).unwrap();
+        let (values, list) = trigger
+            .captures("map Kobra loaded", Some(&regex), LogProfile::Ddnet)
+            .unwrap();
+        assert_eq!(Args::get(&values, "map", String::new()), "Kobra");
+        assert_eq!(list, vec!["map Kobra loaded", "Kobra"]);
+    }
+
+    #[test]
+    fn test_event_injection() {
+        use crate::econ::policy::split_statements;
+
+        let trigger = EventTrigger {
+            event: None,
+            regex: Some(r"^join (?<name>.*) as (?<server_name>\S+)$".to_string()),
+        };
+        let regex = Regex::new(trigger.regex.as_deref().unwrap()).unwrap();
+        let line = r#"join x";shutdown;"\#a;exec evil.cfg as other"#;
+        let (captures, list) = trigger
+            .captures(line, Some(&regex), LogProfile::Ddnet)
+            .unwrap();
+        let args: Value = serde_yaml::from_str("{server_name: server-1}").unwrap();
+
+        let raw = CommandTemplate::Line("say {{server_name}}: hi {{event.name}} ({{1}})".into());
+        let rendered = render_event(&raw, &args, &captures, &list).unwrap();
+        assert_eq!(
+            rendered,
+            "say server-1: hi xshutdownaexec evil.cfg (xshutdownaexec evil.cfg)"
+        );
+        assert_eq!(split_statements(&rendered).len(), 1);
+
+        let structured: CommandTemplate =
+            serde_yaml::from_str(r#"{command: say, args: ["hi {{event.name}}"]}"#).unwrap();
+        let rendered = render_event(&structured, &args, &captures, &list).unwrap();
+        assert_eq!(rendered, r#"say "hi x\";shutdown;\"\\#a;exec evil.cfg""#);
+        assert_eq!(split_statements(&rendered).len(), 1);
+    }
+
+    #[test]
+    fn test_task_variant_by_key() {
+        for yaml in [
+            "{countdown: {at: '2099-01-01 00:00', offsets: 10m}, commands: [shutdown]}",
+            "{countdown: {at: '2099-01-01 00:00'}, commands: shutdown}",
+            "{on: {event: [join]}, commands: [say hi]}",
+            "{cron: [0], commands: [say hi]}",
+        ] {
+            let err = serde_yaml::from_str::<NamedTask>(yaml).unwrap_err();
+            assert!(err.to_string().starts_with("invalid"), "{yaml}: {err}");
+        }
+
+        let task: NamedTask = serde_yaml::from_str(
+            "{name: restart, countdown: {at: '2099-01-01 00:00'}, commands: [shutdown]}",
+        )
+        .unwrap();
+        assert_eq!(task.task.kind(), "countdown");
+        let json = serde_json::to_string(&task).unwrap();
+        let task: NamedTask = serde_json::from_str(&json).unwrap();
+        assert_eq!(task.task.kind(), "countdown");
+        let task: NamedTask = serde_yaml::from_str("{delay: 5, commands: [say hi]}").unwrap();
+        assert_eq!(task.task.kind(), "delay");
+    }
+}
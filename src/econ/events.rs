@@ -0,0 +1,381 @@
+use crate::econ::profile::{LogLine, LogProfile};
+use crate::model::CowStr;
+use crate::util::hardcoded_regex as re;
+use regex::Regex;
+use serde::Serialize;
+use serde_yaml::Value;
+use std::sync::LazyLock;
+
+static CHAT_RE: LazyLock<Regex> = LazyLock::new(|| re(r"^(-?\d+):(-?\d+):(.+?): (.*)$"));
+static WHISPER_RE: LazyLock<Regex> =
+    LazyLock::new(|| re(r"^(-?\d+):(.+?) -> (-?\d+):(.+?): (.*)$"));
+static JOIN_RE: LazyLock<Regex> =
+    LazyLock::new(|| re(r"^team_join player='(\d+):(.*)' team=(-?\d+)$"));
+/// Vanilla servers log `m_Team=` on team changes next to `team=` on connect
+static VANILLA_JOIN_RE: LazyLock<Regex> =
+    LazyLock::new(|| re(r"^team_join player='(\d+):(.*)' (?:m_Team|team)=(-?\d+)$"));
+static LEAVE_RE: LazyLock<Regex> = LazyLock::new(|| re(r"^leave player='(\d+):(.*)'$"));
+static KILL_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(r"^kill killer='(-?\d+):(.*)' victim='(-?\d+):(.*)' weapon=(-?\d+) special=(\d+)$")
+});
+static FINISH_RE: LazyLock<Regex> = LazyLock::new(|| re(r"^\*\*\* '(.+)' finished in: (.+)$"));
+static RENAME_RE: LazyLock<Regex> =
+    LazyLock::new(|| re(r"^\*\*\* '(.+?)' changed name to '(.+)'$"));
+static VOTE_RE: LazyLock<Regex> =
+    LazyLock::new(|| re(r"^\*\*\* '(.+?)' called (?:for )?vote to (.+?)(?: \((.*)\))?$"));
+static MAP_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(r"^(?:loading done\. datafile='maps/(.+)\.map'|maps/(.+)\.map crc is .*)$")
+});
+static RCON_AUTH_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(r"^ClientI[dD]=(\d+) (?:key=(\S+) )?authed(?: with key=(\S+))? \((\w+)\)$")
+});
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EconEvent {
+    Chat {
+        client_id: i32,
+        team: i32,
+        name: String,
+        text: String,
+    },
+    TeamChat {
+        client_id: i32,
+        team: i32,
+        name: String,
+        text: String,
+    },
+    Whisper {
+        from_id: i32,
+        from: String,
+        to_id: i32,
+        to: String,
+        text: String,
+    },
+    Join {
+        client_id: i32,
+        name: String,
+        team: i32,
+    },
+    Leave {
+        client_id: i32,
+        name: String,
+    },
+    Kill {
+        killer_id: i32,
+        killer: String,
+        victim_id: i32,
+        victim: String,
+        weapon: i32,
+        special: i32,
+    },
+    Finish {
+        name: String,
+        time: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Map {
+        name: String,
+    },
+    Vote {
+        name: String,
+        action: String,
+        reason: Option<String>,
+    },
+    RconAuth {
+        client_id: i32,
+        key: Option<String>,
+        level: String,
+    },
+}
+
+fn num(caps: &regex::Captures, index: usize) -> i32 {
+    caps.get(index)
+        .and_then(|m| m.as_str().parse().ok())
+        .unwrap_or(-1)
+}
+
+fn text(caps: &regex::Captures, index: usize) -> String {
+    caps.get(index)
+        .map(|m| m.as_str().to_string())
+        .unwrap_or_default()
+}
+
+impl EconEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EconEvent::Chat { .. } => "chat",
+            EconEvent::TeamChat { .. } => "team_chat",
+            EconEvent::Whisper { .. } => "whisper",
+            EconEvent::Join { .. } => "join",
+            EconEvent::Leave { .. } => "leave",
+            EconEvent::Kill { .. } => "kill",
+            EconEvent::Finish { .. } => "finish",
+            EconEvent::Rename { .. } => "rename",
+            EconEvent::Map { .. } => "map",
+            EconEvent::Vote { .. } => "vote",
+            EconEvent::RconAuth { .. } => "rcon_auth",
+        }
+    }
+
+    pub fn parse(line: &LogLine) -> Option<Self> {
+        let message = line.message;
+        match line.system {
+            "chat" | "teamchat" => {
+                if let Some(caps) = FINISH_RE.captures(message) {
+                    return Some(EconEvent::Finish {
+                        name: text(&caps, 1),
+                        time: text(&caps, 2),
+                    });
+                }
+                if let Some(caps) = RENAME_RE.captures(message) {
+                    return Some(EconEvent::Rename {
+                        from: text(&caps, 1),
+                        to: text(&caps, 2),
+                    });
+                }
+                if let Some(caps) = VOTE_RE.captures(message) {
+                    return Some(EconEvent::Vote {
+                        name: text(&caps, 1),
+                        action: text(&caps, 2),
+                        reason: caps.get(3).map(|m| m.as_str().to_string()),
+                    });
+                }
+                let caps = CHAT_RE.captures(message)?;
+                let (client_id, team, name, text) =
+                    (num(&caps, 1), num(&caps, 2), text(&caps, 3), text(&caps, 4));
+                Some(if line.system == "chat" {
+                    EconEvent::Chat {
+                        client_id,
+                        team,
+                        name,
+                        text,
+                    }
+                } else {
+                    EconEvent::TeamChat {
+                        client_id,
+                        team,
+                        name,
+                        text,
+                    }
+                })
+            }
+            "whisper" => WHISPER_RE.captures(message).map(|caps| EconEvent::Whisper {
+                from_id: num(&caps, 1),
+                from: text(&caps, 2),
+                to_id: num(&caps, 3),
+                to: text(&caps, 4),
+                text: text(&caps, 5),
+            }),
+            "game" => {
+                let join = match line.profile {
+                    LogProfile::Teeworlds06 | LogProfile::Teeworlds07 => &VANILLA_JOIN_RE,
+                    LogProfile::Ddnet | LogProfile::Infclass => &JOIN_RE,
+                };
+                if let Some(caps) = join.captures(message) {
+                    return Some(EconEvent::Join {
+                        client_id: num(&caps, 1),
+                        name: text(&caps, 2),
+                        team: num(&caps, 3),
+                    });
+                }
+                if let Some(caps) = LEAVE_RE.captures(message) {
+                    return Some(EconEvent::Leave {
+                        client_id: num(&caps, 1),
+                        name: text(&caps, 2),
+                    });
+                }
+                KILL_RE.captures(message).map(|caps| EconEvent::Kill {
+                    killer_id: num(&caps, 1),
+                    killer: text(&caps, 2),
+                    victim_id: num(&caps, 3),
+                    victim: text(&caps, 4),
+                    weapon: num(&caps, 5),
+                    special: num(&caps, 6),
+                })
+            }
+            "server" | "datafile" => {
+                if let Some(caps) = RCON_AUTH_RE.captures(message) {
+                    return Some(EconEvent::RconAuth {
+                        client_id: num(&caps, 1),
+                        key: caps.get(2).or(caps.get(3)).map(|m| m.as_str().to_string()),
+                        level: text(&caps, 4),
+                    });
+                }
+                MAP_RE.captures(message).map(|caps| EconEvent::Map {
+                    name: caps
+                        .get(1)
+                        .or(caps.get(2))
+                        .map(|m| m.as_str().to_string())
+                        .unwrap_or_default(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Typed event published next to the raw [`MsgBridge`](crate::econ::model::MsgBridge).
+#[derive(Debug, Clone, Serialize)]
+pub struct EventBridge<'a> {
+    #[serde(flatten)]
+    pub event: EconEvent,
+    pub timestamp: &'a str,
+    pub raw: &'a str,
+    pub args: &'a Value,
+}
+
+impl EventBridge<'_> {
+    pub fn json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+pub fn default_events_subject() -> CowStr<'static> {
+    CowStr::Borrowed("tw.econ.event.{{server_name}}.{{0}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<EconEvent> {
+        EconEvent::parse(&LogProfile::Ddnet.parse(line)?)
+    }
+
+    #[test]
+    fn test_chat() {
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I chat: 3:-2:Sheep: gg: wp"),
+            Some(EconEvent::Chat {
+                client_id: 3,
+                team: -2,
+                name: "Sheep".into(),
+                text: "gg: wp".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I teamchat: 1:0:Sheep: go"),
+            Some(EconEvent::TeamChat {
+                client_id: 1,
+                team: 0,
+                name: "Sheep".into(),
+                text: "go".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I chat: *** 'Sheep' entered and joined the game"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_join_leave_kill() {
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I game: team_join player='4:Sheep' team=0"),
+            Some(EconEvent::Join {
+                client_id: 4,
+                name: "Sheep".into(),
+                team: 0
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I game: leave player='4:Sheep'"),
+            Some(EconEvent::Leave {
+                client_id: 4,
+                name: "Sheep".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I game: kill killer='1:a' victim='2:b' weapon=3 special=0"),
+            Some(EconEvent::Kill {
+                killer_id: 1,
+                killer: "a".into(),
+                victim_id: 2,
+                victim: "b".into(),
+                weapon: 3,
+                special: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_vanilla_join() {
+        let line = "[2024-05-01 12:00:00][game]: team_join player='0:nameless tee' m_Team=0";
+        let join = Some(EconEvent::Join {
+            client_id: 0,
+            name: "nameless tee".into(),
+            team: 0,
+        });
+        let event = LogProfile::Teeworlds07
+            .parse(line)
+            .and_then(|line| EconEvent::parse(&line));
+        assert_eq!(event, join);
+
+        let line = "[663221f0][game]: team_join player='0:nameless tee' m_Team=0";
+        let event = LogProfile::Teeworlds06
+            .parse(line)
+            .and_then(|line| EconEvent::parse(&line));
+        assert_eq!(event, join);
+    }
+
+    #[test]
+    fn test_finish_vote_map_auth() {
+        assert_eq!(
+            parse(
+                "2024-05-01 12:00:00 I chat: *** 'Sheep' finished in: 1 minute(s) 2.34 second(s)"
+            ),
+            Some(EconEvent::Finish {
+                name: "Sheep".into(),
+                time: "1 minute(s) 2.34 second(s)".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I chat: *** 'Sheep' changed name to 'Wolf'"),
+            Some(EconEvent::Rename {
+                from: "Sheep".into(),
+                to: "Wolf".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I chat: *** 'Sheep' called for vote to kick 'Cow' (afk)"),
+            Some(EconEvent::Vote {
+                name: "Sheep".into(),
+                action: "kick 'Cow'".into(),
+                reason: Some("afk".into())
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I datafile: loading done. datafile='maps/Tutorial.map'"),
+            Some(EconEvent::Map {
+                name: "Tutorial".into()
+            })
+        );
+        assert_eq!(
+            parse("2024-05-01 12:00:00 I server: ClientId=2 authed with key=default_admin (admin)"),
+            Some(EconEvent::RconAuth {
+                client_id: 2,
+                key: Some("default_admin".into()),
+                level: "admin".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_event_json() {
+        let args = Value::Null;
+        let msg = EventBridge {
+            event: EconEvent::Map { name: "x".into() },
+            timestamp: "",
+            raw: "raw",
+            args: &args,
+        };
+        let json: serde_json::Value = serde_json::from_str(&msg.json().unwrap()).unwrap();
+        assert_eq!(json["kind"], "map");
+        assert_eq!(json["name"], "x");
+        assert_eq!(json["raw"], "raw");
+    }
+}
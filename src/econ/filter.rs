@@ -0,0 +1,215 @@
+use crate::econ::profile::LogLine;
+use anyhow::anyhow;
+use log::info;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::interval;
+
+/// Rule matching econ lines, every field set has to match.
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct LineRule {
+    /// Log level letters, e.g. `[D, T]`
+    #[serde(default)]
+    pub level: Vec<String>,
+    /// System names, e.g. `[net_ban, ddnet]`
+    #[serde(default)]
+    pub system: Vec<String>,
+    /// Regex matched against the whole line
+    #[serde(default)]
+    pub regex: Option<String>,
+}
+
+/// Lines and events published to NATS, the roster, event tasks and command requests still
+/// see every line.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterConfig {
+    /// Only lines matching one of these rules are published, empty - every line
+    #[serde(default)]
+    pub include: Vec<LineRule>,
+    /// Lines matching one of these rules are dropped, even when included
+    #[serde(default)]
+    pub exclude: Vec<LineRule>,
+    /// Seconds between dropped line counter reports in the log, 0 - never
+    #[serde(default = "default_report")]
+    pub report: u64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            report: default_report(),
+        }
+    }
+}
+
+struct CompiledLineRule {
+    level: Vec<String>,
+    system: Vec<String>,
+    regex: Option<Regex>,
+}
+
+impl CompiledLineRule {
+    fn new(rule: &LineRule) -> anyhow::Result<Self> {
+        let regex = match &rule.regex {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| anyhow!("Invalid filter regex \"{pattern}\": {e}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            level: rule.level.clone(),
+            system: rule.system.clone(),
+            regex,
+        })
+    }
+
+    /// Level and system rules never match lines the profile can't parse.
+    fn matches(&self, line: &str, log_line: Option<&LogLine>) -> bool {
+        let level = self.level.is_empty()
+            || log_line.is_some_and(|l| self.level.iter().any(|level| level == l.level));
+        let system = self.system.is_empty()
+            || log_line.is_some_and(|l| self.system.iter().any(|system| system == l.system));
+        level && system && self.regex.as_ref().is_none_or(|re| re.is_match(line))
+    }
+}
+
+#[derive(Default, Clone, PartialEq)]
+struct FilterCounters {
+    passed: u64,
+    /// Dropped lines by system, empty for lines the profile can't parse
+    dropped: BTreeMap<String, u64>,
+}
+
+/// Include/exclude rules deciding which econ lines are published.
+pub struct LineFilter {
+    include: Vec<CompiledLineRule>,
+    exclude: Vec<CompiledLineRule>,
+    counters: Mutex<FilterCounters>,
+}
+
+impl LineFilter {
+    pub fn new(config: &FilterConfig) -> anyhow::Result<Self> {
+        let compile = |rules: &[LineRule]| {
+            rules
+                .iter()
+                .map(CompiledLineRule::new)
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            include: compile(&config.include)?,
+            exclude: compile(&config.exclude)?,
+            counters: Mutex::default(),
+        })
+    }
+
+    /// `true` when the line is published, counts the dropped ones.
+    pub fn check(&self, line: &str, log_line: Option<&LogLine>) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|r| r.matches(line, log_line));
+        let pass = included && !self.exclude.iter().any(|r| r.matches(line, log_line));
+
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        if pass {
+            counters.passed += 1;
+        } else {
+            let system = log_line.map_or("", |l| l.system);
+            *counters.dropped.entry(system.to_string()).or_default() += 1;
+        }
+        pass
+    }
+
+    fn counters(&self) -> FilterCounters {
+        self.counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Logs the counters of `filter` every `every` when they changed.
+pub async fn report_filter(filter: Arc<LineFilter>, name: String, every: Duration) {
+    let mut ticks = interval(every);
+    let mut reported = FilterCounters::default();
+    loop {
+        ticks.tick().await;
+        let counters = filter.counters();
+        if counters == reported {
+            continue;
+        }
+        let dropped: u64 = counters.dropped.values().sum();
+        let systems: Vec<String> = counters
+            .dropped
+            .iter()
+            .map(|(system, count)| match system.as_str() {
+                "" => format!("unparsed {count}"),
+                system => format!("{system} {count}"),
+            })
+            .collect();
+        info!(
+            "[{name}] line filter: published {}, dropped {dropped} ({})",
+            counters.passed,
+            systems.join(", ")
+        );
+        reported = counters;
+    }
+}
+
+fn default_report() -> u64 {
+    60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::econ::profile::LogProfile;
+
+    fn check(filter: &LineFilter, line: &str) -> bool {
+        filter.check(line, LogProfile::Ddnet.parse(line).as_ref())
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let config: FilterConfig = serde_yaml::from_str(
+            "{include: [{level: [I, W]}, {regex: '^custom'}], \
+             exclude: [{system: [net_ban, server]}, {system: [chat], regex: 'spam'}]}",
+        )
+        .unwrap();
+        let filter = LineFilter::new(&config).unwrap();
+
+        assert!(check(
+            &filter,
+            "2024-05-01 12:00:00 I chat: 0:-2:nick: hello"
+        ));
+        assert!(!check(
+            &filter,
+            "2024-05-01 12:00:00 I chat: 0:-2:nick: spam"
+        ));
+        assert!(!check(&filter, "2024-05-01 12:00:00 D chat: debug"));
+        assert!(!check(&filter, "2024-05-01 12:00:00 I net_ban: banned"));
+        assert!(!check(
+            &filter,
+            "2024-05-01 12:00:00 I server: player ready"
+        ));
+        assert!(check(&filter, "custom line"));
+        assert!(!check(&filter, "other line"));
+
+        let counters = filter.counters();
+        assert_eq!(counters.passed, 2);
+        assert_eq!(counters.dropped["chat"], 2);
+        assert_eq!(counters.dropped[""], 1);
+    }
+
+    #[test]
+    fn test_empty_and_invalid() {
+        let filter = LineFilter::new(&FilterConfig::default()).unwrap();
+        assert!(check(&filter, "anything"));
+        let config: FilterConfig = serde_yaml::from_str("{exclude: [{regex: '('}]}").unwrap();
+        assert!(LineFilter::new(&config).is_err());
+    }
+}
@@ -1,23 +1,40 @@
 use crate::args::Args;
-use crate::econ::model::MsgBridge;
+use crate::econ::command::EconCommand;
+use crate::econ::events::{EconEvent, EventBridge};
+use crate::econ::filter::LineFilter;
+use crate::econ::model::{
+    CommandReply, CommandRequest, EconMessage, EventsConfig, ExitReason, MsgBridge, RequestsConfig,
+};
+use crate::econ::policy::CommandPolicy;
+use crate::econ::process::ProcessOutput;
+use crate::econ::profile::{LogLine, LogProfile};
+use crate::econ::roster::Roster;
+use crate::econ::scheduler::{TaskControl, TaskManager};
+use crate::econ::tail::LogSource;
+use crate::format_values;
 use crate::handler::model::MsgHandler;
 use crate::model::CowStr;
 use crate::nats::Nats;
 use crate::util::convert;
-use async_tw_econ::Econ;
+use anyhow::anyhow;
 use bytes::Bytes;
 use futures_util::StreamExt;
 use log::{debug, error, info, trace, warn};
+use regex::Regex;
 use serde_yaml::Value;
-use std::time::Duration;
-use tokio::sync::mpsc::Sender;
-use tokio::time::sleep;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{sleep, timeout_at};
 
 pub async fn process_messages<'a>(
-    tx: Sender<String>,
+    tx: Sender<EconMessage>,
     nats: Nats,
     subscriber_str: CowStr<'a>,
     queue: CowStr<'a>,
+    policy: Arc<CommandPolicy>,
 ) {
     info!("Subscribe to the channel: {subscriber_str}");
     let mut subscriber = nats.subscriber(subscriber_str, queue).await;
@@ -28,15 +45,37 @@ pub async fn process_messages<'a>(
             message.subject, message.length
         );
         if let Some(msg) = convert::<MsgHandler>(&message.payload) {
-            if Args::get(&msg.args, "econ_divide", false) {
-                for result in msg.value {
-                    if let Err(err) = tx.send(result).await {
-                        error!("tx.send error: {err}");
-                    }
-                }
+            let mut commands = if Args::get(&msg.args, "econ_divide", false) {
+                msg.value
             } else {
-                let result = msg.value.join(" ");
-                if let Err(err) = tx.send(result).await {
+                vec![msg.value.join(" ")]
+            };
+            // Values become quoted arguments of `econ_command` instead of raw console lines
+            let econ_command = Args::get(&msg.args, "econ_command", String::new());
+            if !econ_command.is_empty() {
+                commands = commands
+                    .into_iter()
+                    .filter_map(|value| {
+                        EconCommand::new(econ_command.as_str())
+                            .arg(value)
+                            .to_line()
+                            .inspect_err(|e| warn!("Skipping message: {e}"))
+                            .ok()
+                    })
+                    .collect();
+            }
+            for result in commands {
+                if let Err(reason) = policy.check(&message.subject, &result) {
+                    policy
+                        .reject(&nats, &message.subject, &result, &reason)
+                        .await;
+                    continue;
+                }
+                let econ_message = EconMessage {
+                    command: result,
+                    source: message.subject.to_string(),
+                };
+                if let Err(err) = tx.send(econ_message).await {
                     error!("tx.send error: {err}");
                 }
             }
@@ -44,40 +83,341 @@ pub async fn process_messages<'a>(
     }
 }
 
-pub async fn msg_reader(
-    mut econ: Econ,
-    nats: Nats,
-    nats_path: Vec<CowStr<'static>>,
-    args: Value,
-) -> anyhow::Result<()> {
+#[derive(Clone)]
+pub struct ReaderContext {
+    pub nats: Nats,
+    pub nats_path: Vec<CowStr<'static>>,
+    pub args: Value,
+    pub events: EventsConfig,
+    pub profile: LogProfile,
+    /// Decides which lines and events are published, applied after the roster and requests
+    pub filter: Arc<LineFilter>,
+    /// Every line read from econ, used by command requests to capture output
+    pub lines: broadcast::Sender<String>,
+    /// Updated before a line is broadcast, so tasks see the roster including it
+    pub roster: Roster,
+    /// Read instead of econ when set
+    pub log: Option<LogSource>,
+    /// Stdout of a supervised server, read instead of econ and the log file when set
+    pub process: Option<ProcessOutput>,
+}
+
+/// Handles the lines the econ connection reads, ends with the connection owner.
+pub async fn msg_reader(mut lines: Receiver<String>, ctx: ReaderContext) -> anyhow::Result<()> {
+    while let Some(line) = lines.recv().await {
+        trace!("Message received from econ: {line}");
+        handle_line(&ctx, line).await;
+    }
+    Ok(())
+}
+
+/// Same as [`msg_reader`] with the lines of a server log file.
+pub async fn log_reader(source: LogSource, ctx: ReaderContext) -> anyhow::Result<()> {
+    let mut tail = source.tail.lock().await;
     loop {
-        let line = loop {
-            if let Some(line) = econ.pop_line() {
-                break line;
+        let lines = tail
+            .read_lines()
+            .await
+            .map_err(|e| anyhow!("Reader: log file: {e}"))?;
+        if lines.is_empty() {
+            sleep(source.poll).await;
+        }
+        for line in lines {
+            trace!("Line read from log: {line}");
+            handle_line(&ctx, line).await;
+        }
+    }
+}
+
+/// Same as [`msg_reader`] with the stdout of a supervised server.
+pub async fn process_reader(output: ProcessOutput, ctx: ReaderContext) -> anyhow::Result<()> {
+    let mut lines = output.lines.lock().await;
+    while let Some(line) = lines.recv().await {
+        trace!("Line read from server output: {line}");
+        handle_line(&ctx, line).await;
+    }
+    Err(anyhow!("Reader: server supervisor stopped"))
+}
+
+/// Updates the roster, feeds command requests and publishes the line and its event.
+async fn handle_line(ctx: &ReaderContext, line: String) {
+    let ReaderContext {
+        nats,
+        nats_path,
+        args,
+        events,
+        profile,
+        lines,
+        roster,
+        filter,
+        ..
+    } = ctx;
+    let log_line = profile.parse(&line);
+    let event = log_line.as_ref().and_then(EconEvent::parse);
+    if let Some(log_line) = &log_line {
+        roster.apply(log_line, event.as_ref());
+    }
+    lines.send(line.clone()).ok();
+    if log_line
+        .as_ref()
+        .is_some_and(|log_line| roster.hides(log_line))
+    {
+        trace!("Line of a roster sync: {line}");
+        return;
+    }
+    if !filter.check(&line, log_line.as_ref()) {
+        trace!("Line filtered out: {line}");
+        return;
+    }
+    if let (Some(log_line), Some(event)) = (&log_line, event) {
+        if events.enabled {
+            publish_event(nats, log_line, event, &line, events, args).await;
+        }
+    }
+    let send_msg = MsgBridge {
+        text: line,
+        args: args.clone(),
+    };
+
+    let json = match send_msg.json() {
+        Ok(result) => result,
+        Err(err) => {
+            warn!("Error converting json to string: {err}");
+            return;
+        }
+    };
+
+    let payload = Bytes::from(json);
+    trace!("Sending payload to {nats_path:?}");
+    for patch in nats_path.clone() {
+        nats.publish_bytes(patch, payload.clone()).await.ok();
+    }
+}
+
+async fn publish_event(
+    nats: &Nats,
+    log_line: &LogLine<'_>,
+    event: EconEvent,
+    line: &str,
+    events: &EventsConfig,
+    args: &Value,
+) {
+    let kind = event.kind();
+    let msg = EventBridge {
+        event,
+        timestamp: log_line.timestamp,
+        raw: line,
+        args,
+    };
+    let json = match msg.json() {
+        Ok(result) => result,
+        Err(err) => {
+            warn!("Error converting event to json: {err}");
+            return;
+        }
+    };
+
+    let subject: CowStr = format_values!(events.subject.clone(), args, &[kind]; single);
+    trace!("Sending {kind} event to {subject}");
+    nats.publish_bytes(subject, Bytes::from(json)).await.ok();
+}
+
+pub async fn command_requests(
+    tx: Sender<EconMessage>,
+    lines: broadcast::Sender<String>,
+    nats: Nats,
+    subject: CowStr<'static>,
+    config: RequestsConfig,
+    policy: Arc<CommandPolicy>,
+) {
+    info!("Subscribe to the request channel: {subject}");
+    let mut subscriber = nats.subscriber(subject, CowStr::Borrowed("")).await;
+
+    while let Some(message) = subscriber.next().await {
+        let Some(reply) = message.reply else {
+            warn!(
+                "Command request without reply subject from {}",
+                message.subject
+            );
+            continue;
+        };
+        let request =
+            serde_json::from_slice::<CommandRequest>(&message.payload).unwrap_or_else(|_| {
+                CommandRequest {
+                    command: String::from_utf8_lossy(&message.payload).trim().to_string(),
+                    ..Default::default()
+                }
+            });
+        debug!("Command request received: {}", request.command);
+        if let Err(reason) = policy.check(&message.subject, &request.command) {
+            policy
+                .reject(&nats, &message.subject, &request.command, &reason)
+                .await;
+            let reply_msg = CommandReply {
+                command: request.command,
+                lines: Vec::new(),
+                reason: ExitReason::Rejected,
+                elapsed_ms: 0,
+                error: Some(reason),
+            };
+            if let Ok(json) = serde_json::to_string_pretty(&reply_msg) {
+                nats.nats.publish(reply, Bytes::from(json)).await.ok();
             }
-            if let Err(err) = econ.fetch().await {
-                error!("Reader: err from loop: {err}");
-                sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let tx = tx.clone();
+        let nats = nats.clone();
+        let output = lines.subscribe();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let source = message.subject.to_string();
+            let result = execute_command(tx, output, request, source, &config).await;
+            let json = match serde_json::to_string_pretty(&result) {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!("Error converting reply to json: {err}");
+                    return;
+                }
+            };
+            if let Err(err) = nats.nats.publish(reply, Bytes::from(json)).await {
+                error!("Failed to reply to command request: {err}");
             }
+        });
+    }
+}
+
+async fn execute_command(
+    tx: Sender<EconMessage>,
+    mut output: broadcast::Receiver<String>,
+    request: CommandRequest,
+    source: String,
+    config: &RequestsConfig,
+) -> CommandReply {
+    let started = Instant::now();
+    let mut reply = CommandReply {
+        command: request.command.clone(),
+        lines: Vec::new(),
+        reason: ExitReason::Timeout,
+        elapsed_ms: 0,
+        error: None,
+    };
+
+    if request.command.is_empty() {
+        reply.reason = ExitReason::Error;
+        reply.error = Some("Empty command".to_string());
+        return reply;
+    }
+
+    let terminator = match request.terminator.as_ref().or(config.terminator.as_ref()) {
+        Some(pattern) => match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(err) => {
+                reply.reason = ExitReason::Error;
+                reply.error = Some(format!("Invalid terminator \"{pattern}\": {err}"));
+                return reply;
+            }
+        },
+        None => None,
+    };
+    let quiet = Duration::from_millis(request.quiet.unwrap_or(config.quiet));
+    let deadline = tokio::time::Instant::now()
+        + Duration::from_millis(request.timeout.unwrap_or(config.timeout));
+
+    let message = EconMessage {
+        command: request.command,
+        source,
+    };
+    if let Err(err) = tx.send(message).await {
+        reply.reason = ExitReason::Error;
+        reply.error = Some(format!("Failed to queue command: {err}"));
+        return reply;
+    }
+
+    reply.reason = loop {
+        // A queued, rate limited or spooled command has no output yet, only `timeout` applies
+        let wait_until = match reply.lines.is_empty() {
+            true => deadline,
+            false => deadline.min(tokio::time::Instant::now() + quiet),
         };
-        trace!("Message received from econ: {line}");
-        let send_msg = MsgBridge {
-            text: line,
-            args: args.clone(),
-        };
+        match timeout_at(wait_until, output.recv()).await {
+            Ok(Ok(line)) => {
+                let done = terminator.as_ref().is_some_and(|re| re.is_match(&line));
+                reply.lines.push(line);
+                if done {
+                    break ExitReason::Terminator;
+                }
+            }
+            Ok(Err(RecvError::Lagged(count))) => {
+                warn!("Command request output lagged, {count} lines lost");
+            }
+            Ok(Err(RecvError::Closed)) => break ExitReason::Error,
+            Err(_) if wait_until < deadline => break ExitReason::Quiet,
+            Err(_) => break ExitReason::Timeout,
+        }
+    };
+    reply.elapsed_ms = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX);
+    reply
+}
 
-        let json = match send_msg.json() {
+pub async fn task_control(nats: Nats, subject: CowStr<'static>, manager: Arc<Mutex<TaskManager>>) {
+    info!("Subscribe to the task control channel: {subject}");
+    let mut subscriber = nats.subscriber(subject, CowStr::Borrowed("")).await;
+
+    while let Some(message) = subscriber.next().await {
+        let Some(reply) = message.reply else {
+            warn!(
+                "Task control request without reply subject from {}",
+                message.subject
+            );
+            continue;
+        };
+        let Some(request) = convert::<TaskControl>(&message.payload) else {
+            continue;
+        };
+        debug!("Task control request received: {:?}", request.action);
+        let result = manager.lock().await.handle(request).await;
+        let json = match serde_json::to_string_pretty(&result) {
             Ok(result) => result,
             Err(err) => {
-                warn!("Error converting json to string: {err}");
+                warn!("Error converting reply to json: {err}");
                 continue;
             }
         };
-
-        let payload = Bytes::from(json);
-        trace!("Sending payload to {nats_path:?}");
-        for patch in nats_path.clone() {
-            nats.publish_bytes(patch, payload.clone()).await.ok();
+        if let Err(err) = nats.nats.publish(reply, Bytes::from(json)).await {
+            error!("Failed to reply to task control request: {err}");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_quiet_starts_with_output() {
+        let (tx, mut rx) = mpsc::channel::<EconMessage>(1);
+        let (lines, output) = broadcast::channel(8);
+        let config = RequestsConfig {
+            quiet: 50,
+            timeout: 2000,
+            ..Default::default()
+        };
+        let request = CommandRequest {
+            command: "status".to_string(),
+            ..Default::default()
+        };
+        let econ = tokio::spawn(async move {
+            let command = rx.recv().await.unwrap().command;
+            // Held back longer than `quiet` before econ prints anything
+            sleep(Duration::from_millis(200)).await;
+            lines.send(format!("{command} output")).unwrap();
+            lines
+        });
+        let reply = execute_command(tx, output, request, "test".to_string(), &config).await;
+        assert!(matches!(reply.reason, ExitReason::Quiet));
+        assert_eq!(reply.lines, ["status output"]);
+        econ.await.unwrap();
+    }
+}
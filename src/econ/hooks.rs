@@ -0,0 +1,150 @@
+use crate::args::Args;
+use crate::econ::command::CommandTemplate;
+use crate::econ::connection::EconConnection;
+use crate::econ::model::EconConfig;
+use crate::econ::timing::format_duration;
+use crate::format_values;
+use crate::model::CowStr;
+use crate::nats::Nats;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Commands and event of a connection change.
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct HookConfig {
+    /// Sent to econ right after connecting, {{downtime}} - outage duration, {{seconds}} - the same in seconds
+    #[serde(default)]
+    pub commands: Vec<CommandTemplate>,
+    /// Subject receiving `server.up`/`server.down` as json
+    #[serde(default)]
+    pub subject: Option<CowStr<'static>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServerEvent<'a> {
+    kind: &'static str,
+    /// Unix time in seconds
+    timestamp: i64,
+    /// Seconds without econ, only after an outage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    downtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    args: &'a Value,
+}
+
+/// Connects to econ and runs the hooks, keeps the outage state across pipeline restarts.
+pub struct ConnectionHooks {
+    nats: Nats,
+    args: Value,
+    first_commands: Vec<CommandTemplate>,
+    on_connect: HookConfig,
+    on_disconnect: HookConfig,
+    /// Start of the current outage, `None` while connected and before the first connection
+    down_since: Option<DateTime<Utc>>,
+    /// `server.up` was published for the current connection
+    up: bool,
+    /// Notified after every connection, a permit is kept until the next pipeline picks it up
+    connected: Arc<Notify>,
+}
+
+impl ConnectionHooks {
+    pub fn new(econ: &EconConfig, args: Value, nats: Nats) -> Self {
+        Self {
+            nats,
+            args,
+            first_commands: econ.first_commands.clone(),
+            on_connect: econ.on_connect.clone(),
+            on_disconnect: econ.on_disconnect.clone(),
+            down_since: None,
+            up: false,
+            connected: Arc::default(),
+        }
+    }
+
+    pub fn connected(&self) -> Arc<Notify> {
+        self.connected.clone()
+    }
+
+    /// Opens a connection and sends `first_commands`, the `on_disconnect` commands after an
+    /// outage, then the `on_connect` ones.
+    pub async fn connect(&mut self, econ: &EconConfig) -> anyhow::Result<EconConnection> {
+        let mut connection = EconConnection::connect(econ, &self.args).await?;
+        let now = Utc::now();
+        let downtime = self.down_since.map(|since| now - since);
+
+        let mut values = Mapping::new();
+        let duration = downtime.unwrap_or_default();
+        values.insert("downtime".into(), format_duration(duration).into());
+        values.insert("seconds".into(), duration.num_seconds().into());
+        let args = Args::merge_yaml_values(&self.args, &Value::Mapping(values));
+
+        let after_outage = downtime.map_or(&[][..], |_| &self.on_disconnect.commands[..]);
+        let commands = self
+            .first_commands
+            .iter()
+            .chain(after_outage)
+            .chain(&self.on_connect.commands);
+        for command in commands {
+            connection
+                .send_line(&command.render(&args, &[] as &[&str])?)
+                .await?;
+        }
+
+        self.down_since = None;
+        self.up = true;
+        self.connected.notify_one();
+        let event = ServerEvent {
+            kind: "server.up",
+            timestamp: now.timestamp(),
+            downtime: downtime.map(|downtime| downtime.num_seconds()),
+            error: None,
+            args: &self.args,
+        };
+        self.publish(self.on_connect.subject.as_ref(), &event).await;
+        Ok(connection)
+    }
+
+    /// Starts an outage, `server.down` is published once and only after `server.up`.
+    pub async fn disconnected(&mut self, error: &str) {
+        if !self.up {
+            return;
+        }
+        self.up = false;
+        let now = Utc::now();
+        self.down_since = Some(now);
+        let event = ServerEvent {
+            kind: "server.down",
+            timestamp: now.timestamp(),
+            downtime: None,
+            error: Some(error),
+            args: &self.args,
+        };
+        self.publish(self.on_disconnect.subject.as_ref(), &event)
+            .await;
+    }
+
+    async fn publish(&self, subject: Option<&CowStr<'static>>, event: &ServerEvent<'_>) {
+        let Some(subject) = subject else {
+            return;
+        };
+        let json = match serde_json::to_string_pretty(event) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Error converting server event to json: {err}");
+                return;
+            }
+        };
+        let subject: CowStr = format_values!(subject, &self.args, &[event.kind]; single);
+        trace!("Sending {} to {subject}", event.kind);
+        self.nats
+            .publish_bytes(subject, Bytes::from(json))
+            .await
+            .ok();
+    }
+}
@@ -1,43 +1,144 @@
+mod chunk;
+mod command;
+mod connection;
 mod enums;
+mod events;
+mod filter;
 mod handlers;
+mod hooks;
 pub mod model;
+mod policy;
+mod priority;
+mod process;
+mod profile;
+mod rate_limit;
+pub mod roster;
+mod rotation;
+mod scheduler;
+mod spool;
+mod tail;
+mod timing;
 
-use crate::econ::handlers::{msg_reader, process_messages};
-use crate::econ::model::ConfigEcon;
+use crate::econ::connection::EconConnection;
+use crate::econ::enums::TaskContext;
+use crate::econ::filter::{report_filter, LineFilter};
+use crate::econ::handlers::{
+    command_requests, log_reader, msg_reader, process_messages, process_reader, task_control,
+    ReaderContext,
+};
+use crate::econ::hooks::ConnectionHooks;
+use crate::econ::model::{
+    ConfigEcon, EconConfig, EconMessage, ServerConfig, ROSTER_SOURCE, TASK_SOURCE,
+};
+use crate::econ::policy::CommandPolicy;
+use crate::econ::priority::Priority;
+use crate::econ::process::{process_control, spawn_process, ProcessOutput};
+use crate::econ::rate_limit::{merge_commands, Decision, RateLimiter};
+use crate::econ::roster::{publish_roster, sync_roster, Roster, RosterOutput};
+use crate::econ::scheduler::{TaskManager, TaskStore};
+use crate::econ::spool::Spool;
 use crate::format_values;
 use crate::model::{BaseConfig, CowStr};
 use crate::nats::Nats;
-use async_tw_econ::Econ;
+use anyhow::anyhow;
+use futures_util::future::join_all;
 use log::{debug, error, info, warn};
-use serde_yaml::Value;
 use std::collections::HashSet;
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::sleep;
 
 pub async fn main(config_path: String) -> anyhow::Result<()> {
     let config = ConfigEcon::load_yaml(&config_path).await?;
     config.set_logging();
 
-    let (tx, mut rx) = mpsc::channel(64);
+    let servers = config.servers();
+    if servers.is_empty() {
+        return Err(anyhow!(
+            "No econ servers configured, set `econ` or `servers`"
+        ));
+    }
+    for server in &servers {
+        server
+            .econ
+            .validate(&server.args.clone().unwrap_or_default())
+            .map_err(|e| anyhow!("[{}] {e}", server.name()))?;
+    }
     let nats = config.connect_nats().await?;
 
-    let mut econ_write = config.econ_connect().await?;
-    info!("econ connected");
+    let handles: Vec<_> = servers
+        .into_iter()
+        .map(|server| tokio::spawn(supervise_server(server, nats.clone())))
+        .collect();
+    join_all(handles).await;
 
-    let args = config.args.clone().unwrap_or_default();
-    let first_commands: Vec<CowStr> = format_values!(
-        config.econ.first_commands.clone(),
-        &args,
-        &[] as &[&str],
-        Vec::new()
-    );
-    for command in first_commands {
-        econ_write.send_line(command.to_string()).await?;
+    Ok(())
+}
+
+/// Keeps the pipeline of a single server alive, failures never leave this task.
+async fn supervise_server(server: ServerConfig<'static>, nats: Nats) {
+    let name = server.name();
+    // The game server outlives restarts of the pipeline
+    let process = server.econ.process.clone().map(|config| {
+        let control = config.control.clone();
+        let (output, handle) = spawn_process(config, name.clone());
+        if control.enabled {
+            let args = server.args.clone().unwrap_or_default();
+            let subject: CowStr = format_values!(control.subject, &args, &[] as &[&str]; single);
+            tokio::spawn(process_control(nats.clone(), subject, handle.clone()));
+        }
+        (output, handle)
+    });
+    let args = server.args.clone().unwrap_or_default();
+    let mut hooks = ConnectionHooks::new(&server.econ, args, nats.clone());
+    loop {
+        let output = process.as_ref().map(|(output, _)| output.clone());
+        let error = match run_server(&server, nats.clone(), output, &mut hooks).await {
+            Ok(()) => {
+                warn!("[{name}] econ pipeline stopped");
+                "pipeline stopped".to_string()
+            }
+            Err(err) => {
+                error!("[{name}] econ pipeline failed: {err}");
+                err.to_string()
+            }
+        };
+        if server.econ.sends_commands() {
+            hooks.disconnected(&error).await;
+        }
+        sleep(Duration::from_secs(server.econ.reconnect.sleep)).await;
     }
+}
+
+async fn run_server(
+    server: &ServerConfig<'static>,
+    nats: Nats,
+    process: Option<ProcessOutput>,
+    hooks: &mut ConnectionHooks,
+) -> anyhow::Result<()> {
+    let name = server.name();
+    let econ = &server.econ;
+    let args = server.args.clone().unwrap_or_default();
+    // Dropping the set aborts every task of this server
+    let mut tasks = JoinSet::new();
+
+    let (tx, mut rx) = mpsc::channel(64);
+
+    // A log file or process input may come without any econ connection
+    let commands = econ.sends_commands();
+    let connection = if commands {
+        let connection = hooks.connect(econ).await?;
+        info!("[{name}] econ connected");
+        Some(connection)
+    } else {
+        None
+    };
 
     let read_path: Vec<CowStr> = format_values!(
-        config.nats.from.clone(),
+        server.from.clone(),
         &args,
         &[] as &[&str],
         vec![
@@ -46,129 +147,494 @@ pub async fn main(config_path: String) -> anyhow::Result<()> {
         ]
     );
     let write_path: Vec<CowStr> = format_values!(
-        config.nats.to.clone(),
+        server.to.clone(),
         &args,
         &[] as &[&str],
         vec![CowStr::Borrowed("tw.econ.read.{{message_thread_id}}")]
     );
     let queue: CowStr = format_values!(
-        config.nats.queue.clone(),
+        server.queue.clone(),
         &args,
         &[] as &[&str],
         CowStr::Borrowed("econ.reader");
         single
     );
 
-    let reader = tokio::spawn(msg_reader(
-        config.econ_connect().await?,
-        nats.clone(),
-        write_path.clone(),
-        args.clone(),
-    ));
-    for path in read_path {
-        tokio::spawn(process_messages(
+    let rejected = econ
+        .policy
+        .rejected
+        .as_ref()
+        .map(|subject| -> CowStr<'static> {
+            format_values!(subject, &args, &[] as &[&str]; single)
+        });
+    let policy = Arc::new(CommandPolicy::new(&econ.policy, rejected)?);
+
+    let filter = Arc::new(LineFilter::new(&econ.filter)?);
+    if econ.filter.report != 0 {
+        let every = Duration::from_secs(econ.filter.report);
+        tasks.spawn(report_filter(filter.clone(), name.clone(), every));
+    }
+
+    let (lines, _) = broadcast::channel(256);
+    let roster = Roster::default();
+    let reader_ctx = ReaderContext {
+        nats: nats.clone(),
+        nats_path: write_path,
+        args: args.clone(),
+        events: econ.events.clone(),
+        profile: econ.profile,
+        filter,
+        lines: lines.clone(),
+        roster: roster.clone(),
+        log: econ.log.as_ref().map(|log| log.source(&args)),
+        process,
+    };
+    let (mut reader, econ_lines) = start_reader(reader_ctx);
+    if econ.requests.enabled && commands {
+        let subject: CowStr = format_values!(
+            econ.requests.subject.clone(),
+            &args,
+            &[] as &[&str];
+            single
+        );
+        tasks.spawn(command_requests(
             tx.clone(),
+            lines.clone(),
             nats.clone(),
-            path,
-            queue.clone(),
+            subject,
+            econ.requests.clone(),
+            policy.clone(),
+        ));
+    }
+    if commands {
+        for path in read_path {
+            tasks.spawn(process_messages(
+                tx.clone(),
+                nats.clone(),
+                path,
+                queue.clone(),
+                policy.clone(),
+            ));
+        }
+    }
+    let task_ctx = TaskContext {
+        tx: tx.clone(),
+        args: args.clone(),
+        profile: econ.profile,
+        lines: lines.clone(),
+        roster: roster.clone(),
+    };
+    if commands {
+        tasks.spawn(sync_roster(
+            tx.clone(),
+            roster.clone(),
+            lines.clone(),
+            econ.profile,
+            hooks.connected(),
+            econ.roster.status_interval,
+        ));
+    }
+    if econ.roster.subject.is_some() || econ.roster.kv_bucket.is_some() {
+        let store = match &econ.roster.kv_bucket {
+            Some(bucket) => Some((
+                nats.key_value(bucket).await?,
+                format_values!(econ.roster.kv_key, &args, &[] as &[&str]; single).into_owned(),
+            )),
+            None => None,
+        };
+        let output = RosterOutput {
+            server: name.clone(),
+            subject: econ
+                .roster
+                .subject
+                .as_ref()
+                .map(|subject| format_values!(subject, &args, &[] as &[&str]; single)),
+            store,
+            args: args.clone(),
+        };
+        tasks.spawn(publish_roster(nats.clone(), roster, output));
+    }
+    let task_store = match &econ.control.kv_bucket {
+        Some(bucket) => Some(TaskStore {
+            store: nats.key_value(bucket).await?,
+            key: format_values!(econ.control.kv_key, &args, &[] as &[&str]; single).into_owned(),
+        }),
+        None => None,
+    };
+    let control_subject: CowStr = format_values!(
+        econ.control.subject.clone(),
+        &args,
+        &[] as &[&str];
+        single
+    );
+    let mut task_manager = TaskManager::new(
+        task_ctx,
+        task_store,
+        policy.clone(),
+        control_subject.to_string(),
+    );
+    task_manager.start(econ.tasks.clone()).await;
+    let task_manager = Arc::new(Mutex::new(task_manager));
+    if econ.control.enabled {
+        tasks.spawn(task_control(
+            nats.clone(),
+            control_subject,
+            task_manager.clone(),
         ));
     }
-    let mut tasks = config.econ.clone().tasks;
-    tasks.reverse();
 
-    for t in tasks {
-        let task_tx = tx.clone();
-        let mut task = t;
-        task.init_state();
+    let Some(connection) = connection else {
+        let result = run_read_only(&mut reader, &name, &mut rx).await;
+        reader.abort();
+        tasks.shutdown().await;
+        return result;
+    };
 
-        tokio::spawn(async move {
-            task.execute(&task_tx).await;
-        });
+    let spool_path = econ.spool.path.as_ref().map(|path| {
+        let path: CowStr = format_values!(path, &args, &[] as &[&str]; single);
+        PathBuf::from(path.as_ref())
+    });
+    let spool = Spool::new(spool_path, econ.spool.max_commands, econ.spool.expire)
+        .load()
+        .await;
+    if !spool.is_empty() {
+        info!("[{name}] Replaying {} spooled commands", spool.len());
+    }
+
+    let result = run_message_loop(
+        connection,
+        &mut reader,
+        econ_lines,
+        server,
+        spool,
+        hooks,
+        &mut rx,
+    )
+    .await;
+    reader.abort();
+    tasks.shutdown().await;
+    result
+}
+
+/// Handles the server stdout or log file when one is configured, the econ output otherwise.
+///
+/// Returns the sender econ lines are forwarded to, `None` when they are not handled.
+fn start_reader(
+    ctx: ReaderContext,
+) -> (JoinHandle<anyhow::Result<()>>, Option<mpsc::Sender<String>>) {
+    match (ctx.process.clone(), ctx.log.clone()) {
+        (Some(output), _) => (tokio::spawn(process_reader(output, ctx)), None),
+        (None, Some(source)) => (tokio::spawn(log_reader(source, ctx)), None),
+        (None, None) => {
+            let (tx, rx) = mpsc::channel(1024);
+            (tokio::spawn(msg_reader(rx, ctx)), Some(tx))
+        }
     }
+}
 
-    run_message_loop(econ_write, reader, &config, nats, write_path, args, &mut rx).await
+/// Log or process input without econ, commands have nowhere to go and are dropped.
+async fn run_read_only(
+    reader: &mut JoinHandle<anyhow::Result<()>>,
+    name: &str,
+    rx: &mut mpsc::Receiver<EconMessage>,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => debug!("[{name}] Commands are disabled, dropping: {}", message.command),
+                None => return Ok(()),
+            },
+            result = &mut *reader => {
+                return result.map_err(|e| anyhow!("reader panicked: {e}"))?;
+            }
+        }
+    }
 }
 
+/// Owns the econ connection, multiplexing its output with the commands written to it.
 async fn run_message_loop(
-    mut econ_write: Econ,
-    mut reader: JoinHandle<anyhow::Result<()>>,
-    config: &ConfigEcon<'_>,
-    nats: Nats,
-    write_path: Vec<CowStr<'static>>,
-    args: Value,
-    rx: &mut mpsc::Receiver<String>,
+    connection: EconConnection,
+    reader: &mut JoinHandle<anyhow::Result<()>>,
+    econ_lines: Option<mpsc::Sender<String>>,
+    server: &ServerConfig<'_>,
+    mut spool: Spool,
+    hooks: &mut ConnectionHooks,
+    rx: &mut mpsc::Receiver<EconMessage>,
 ) -> anyhow::Result<()> {
-    let mut pending_messages = Vec::new();
-    let mut reconnect_attempt = 0;
+    let name = server.name();
+    let mut limiter = RateLimiter::new(&server.econ.rate_limit);
+    let max_bytes = server.econ.chunking.max_bytes;
+    // `None` while disconnected, the only health state of the server
+    let mut connection = Some(connection);
 
-    let tasks_messages: HashSet<String> = config
-        .econ
-        .tasks
-        .iter()
-        .flat_map(enums::Task::get_all_commands)
-        .collect();
+    loop {
+        let expired = spool.drop_expired(chrono::Utc::now().timestamp());
+        if expired != 0 {
+            warn!("[{name}] Dropped {expired} expired commands");
+        }
+        let mut wait = None;
+        if let Some(econ) = &mut connection {
+            match flush(econ, &mut spool, &mut limiter, max_bytes).await {
+                Ok(result) => wait = result,
+                Err(err) => {
+                    error!("[{name}] Error sending to econ: {err}");
+                    hooks.disconnected(&err.to_string()).await;
+                    connection = None;
+                }
+            }
+            limiter.report(&name, Instant::now());
+        }
+        spool.sync().await;
 
-    while let Some(message) = rx.recv().await {
-        if reconnect_attempt != 0 && tasks_messages.contains(&message) {
-            debug!("Skipping task command during reconnect: {message}");
+        let Some(econ) = &mut connection else {
+            connection = match reconnect(server, hooks, rx, &mut spool).await {
+                Some(econ) => Some(econ),
+                None => break,
+            };
             continue;
-        }
-        pending_messages.push(message.clone());
+        };
 
-        while !pending_messages.is_empty() {
-            match econ_write.send_line(&pending_messages[0]).await {
-                Ok(()) => {
-                    pending_messages.remove(0);
-                    reconnect_attempt = 0;
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => enqueue(&mut spool, &server.econ, message),
+                None => break,
+            },
+            lines = econ.read_lines() => match lines {
+                Ok(lines) => {
+                    let Some(econ_lines) = &econ_lines else {
+                        // Drained so the server never blocks on a full socket
+                        continue;
+                    };
+                    for line in lines {
+                        if econ_lines.send(line).await.is_err() {
+                            return Err(anyhow!("econ reader stopped"));
+                        }
+                    }
                 }
                 Err(err) => {
-                    error!("Error sending to econ: {err}");
-
-                    reader.abort();
-                    reconnect_attempt += 1;
-                    if reconnect_attempt > config.econ.reconnect.max_attempts {
-                        error!(
-                            "Max reconnect attempts reached. Dropping {} pending messages",
-                            pending_messages.len()
+                    error!("[{name}] {err}");
+                    hooks.disconnected(&err.to_string()).await;
+                    connection = None;
+                }
+            },
+            result = &mut *reader => {
+                return match result {
+                    Ok(Ok(())) => Err(anyhow!("reader stopped")),
+                    Ok(Err(err)) => Err(err),
+                    Err(err) => Err(anyhow!("reader panicked: {err}")),
+                };
+            }
+            () = sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+        }
+    }
+    Ok(())
+}
+
+/// Splits long chat commands and queues them in the lane of their source and command.
+///
+/// Roster syncs are high priority, a limited or evicted `status` would leave the roster stale.
+fn enqueue(spool: &mut Spool, econ: &EconConfig, message: EconMessage) {
+    let priority = match message.source.as_str() {
+        ROSTER_SOURCE => Priority::High,
+        source => econ.priority.classify(source, &message.command),
+    };
+    let commands = econ.chunking.split(message.command);
+    let part = commands.len() > 1;
+    for command in commands {
+        spool.push(command, message.source.clone(), priority, part);
+    }
+}
+
+/// Sends every spooled command the rate limiter lets through, high priority ones bypass it.
+///
+/// Returns how long to wait before commands held back can be retried.
+async fn flush(
+    econ: &mut EconConnection,
+    spool: &mut Spool,
+    limiter: &mut RateLimiter,
+    max_bytes: usize,
+) -> std::io::Result<Option<Duration>> {
+    let now = Instant::now();
+    let mut wait: Option<Duration> = None;
+
+    // Held commands stay spooled until the merged line of their source is sent
+    let mut released = HashSet::new();
+    let mut index = 0;
+    while let Some(entry) = spool.get(index) {
+        if !entry.held || !released.insert(entry.source.clone()) {
+            index += 1;
+            continue;
+        }
+        let source = entry.source.clone();
+        match limiter.release(&source, now) {
+            Ok(()) => {
+                let held = spool.held(&source);
+                let commands: Vec<(&str, bool)> = held
+                    .iter()
+                    .filter_map(|&i| spool.get(i).map(|c| (c.command.as_str(), c.part)))
+                    .collect();
+                let (line, merged) = merge_commands(&commands, max_bytes);
+                econ.send_line(&line).await?;
+                for &i in held[..merged].iter().rev() {
+                    spool.remove(i);
+                }
+            }
+            Err(after) => {
+                wait = Some(wait.map_or(after, |wait| wait.min(after)));
+                index += 1;
+            }
+        }
+    }
+
+    // Sources waiting for a token keep their order, other sources may pass them
+    let mut blocked = HashSet::new();
+    let mut index = 0;
+    while let Some(entry) = spool.get(index) {
+        if entry.priority == Priority::High {
+            econ.send_line(&entry.command).await?;
+            spool.remove(index);
+            continue;
+        }
+        if entry.held || blocked.contains(&entry.source) {
+            index += 1;
+            continue;
+        }
+        match limiter.check(&entry.source, !entry.delayed, now) {
+            Decision::Send => {
+                econ.send_line(&entry.command).await?;
+                spool.remove(index);
+            }
+            Decision::Held => {
+                let after = limiter.release_wait(&entry.source, now);
+                wait = Some(wait.map_or(after, |wait| wait.min(after)));
+                spool.mark_held(index);
+                index += 1;
+            }
+            Decision::Drop => spool.remove(index),
+            Decision::Wait { after, global } => {
+                wait = Some(wait.map_or(after, |wait| wait.min(after)));
+                let source = entry.source.clone();
+                spool.mark_delayed(index);
+                if global {
+                    break;
+                }
+                blocked.insert(source);
+                index += 1;
+            }
+        }
+    }
+    Ok(wait)
+}
+
+/// Reconnects with backoff, commands arriving meanwhile are queued.
+///
+/// Returns `None` once every command sender is gone.
+async fn reconnect(
+    server: &ServerConfig<'_>,
+    hooks: &mut ConnectionHooks,
+    rx: &mut mpsc::Receiver<EconMessage>,
+    spool: &mut Spool,
+) -> Option<EconConnection> {
+    let name = server.name();
+    let econ = &server.econ;
+    let mut reconnect_attempt = 0;
+
+    loop {
+        reconnect_attempt += 1;
+        // A persistent spool keeps commands until they expire instead
+        if reconnect_attempt > econ.reconnect.max_attempts
+            && !spool.is_persistent()
+            && !spool.is_empty()
+        {
+            error!(
+                "[{}] Max reconnect attempts reached. Dropping {} pending messages",
+                name,
+                spool.len()
+            );
+            spool.clear();
+        }
+
+        warn!(
+            "[{}] Attempting to reconnect (attempt {}/{})",
+            name, reconnect_attempt, econ.reconnect.max_attempts
+        );
+
+        match hooks.connect(econ).await {
+            Ok(connection) => {
+                info!("[{name}] Reconnected successfully");
+                return Some(connection);
+            }
+            Err(e) => error!("[{name}] Reconnect failed: {e}"),
+        }
+
+        let backoff = sleep(econ.reconnect.backoff(reconnect_attempt));
+        tokio::pin!(backoff);
+        loop {
+            tokio::select! {
+                () = &mut backoff => break,
+                message = rx.recv() => match message {
+                    Some(message) if message.source == TASK_SOURCE => {
+                        debug!(
+                            "[{name}] Skipping task command during reconnect: {}",
+                            message.command
                         );
-                        pending_messages.clear();
-                        reconnect_attempt = 0;
-                        break;
                     }
-
-                    warn!(
-                        "Attempting to reconnect (attempt {}/{})",
-                        reconnect_attempt, config.econ.reconnect.max_attempts
-                    );
-
-                    match config.econ.econ_connect(Some(&args)).await {
-                        Ok(result) => {
-                            reconnect_attempt = 0;
-                            match config.econ_connect().await {
-                                Ok(econ) => {
-                                    reader = tokio::spawn(msg_reader(
-                                        econ,
-                                        nats.clone(),
-                                        write_path.clone(),
-                                        args.clone(),
-                                    ));
-                                }
-                                Err(e) => {
-                                    error!("econ_reader reconnect failed: {e}");
-                                }
-                            }
-                            econ_write = result;
-                            info!("Reconnected successfully");
-                            continue;
-                        }
-                        Err(e) => {
-                            error!("Reconnect failed: {e}");
-                        }
+                    Some(message) => {
+                        enqueue(spool, econ, message);
+                        spool.sync().await;
                     }
-                    tokio::time::sleep(Duration::from_secs(config.econ.reconnect.sleep)).await;
-                }
+                    None => return None,
+                },
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_roster_sync_bypasses_task_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(client, listener.accept());
+        let mut econ = EconConnection::new(client.unwrap());
+        let mut received = BufReader::new(server.unwrap().0).lines();
+
+        let config: EconConfig = serde_yaml::from_str(
+            "{rate_limit: {subjects: [{subjects: [task, roster], rate: 0.001, burst: 1, policy: drop}]}, \
+             priority: {rules: [{subjects: [task, roster], priority: low}]}}",
+        )
+        .unwrap();
+        let mut limiter = RateLimiter::new(&config.rate_limit);
+        let mut spool = Spool::new(None, 1, 0);
+        let message = |command: &str, source: &str| EconMessage {
+            command: command.to_string(),
+            source: source.to_string(),
+        };
+
+        enqueue(&mut spool, &config, message("say a", TASK_SOURCE));
+        flush(&mut econ, &mut spool, &mut limiter, 255)
+            .await
+            .unwrap();
+        // The task bucket is empty and the spool full of low priority commands
+        enqueue(&mut spool, &config, message("say b", TASK_SOURCE));
+        enqueue(
+            &mut spool,
+            &config,
+            message("status; echo sync", ROSTER_SOURCE),
+        );
+        enqueue(&mut spool, &config, message("say c", TASK_SOURCE));
+        flush(&mut econ, &mut spool, &mut limiter, 255)
+            .await
+            .unwrap();
+        assert!(spool.is_empty());
+
+        for expected in ["say a", "status; echo sync"] {
+            assert_eq!(received.next_line().await.unwrap().unwrap(), expected);
+        }
+    }
 }
@@ -1,16 +1,25 @@
-use crate::econ::enums::Task;
+use crate::args::Args;
+use crate::econ::chunk::ChunkConfig;
+use crate::econ::command::CommandTemplate;
+use crate::econ::enums::NamedTask;
+use crate::econ::events::default_events_subject;
+use crate::econ::filter::FilterConfig;
+use crate::econ::hooks::HookConfig;
+use crate::econ::policy::PolicyConfig;
+use crate::econ::priority::PriorityConfig;
+use crate::econ::process::ProcessConfig;
+use crate::econ::profile::LogProfile;
+use crate::econ::rate_limit::RateLimitConfig;
+use crate::econ::tail::LogSourceConfig;
 use crate::format::formatting;
 use crate::model::{BaseConfig, CowStr};
 use crate::nats::NatsConfig;
 use anyhow::anyhow;
-use async_tw_econ::Econ;
-use log::warn;
 use nestify::nest;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MsgBridge {
@@ -24,36 +33,242 @@ impl MsgBridge {
     }
 }
 
+pub const TASK_SOURCE: &str = "task";
+
+/// Source of the `status` requests syncing the roster, never rate limited or dropped first.
+pub const ROSTER_SOURCE: &str = "roster";
+
+/// Command queued for econ together with the subject it came from.
+#[derive(Debug, Clone)]
+pub struct EconMessage {
+    pub command: String,
+    pub source: String,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct CommandRequest {
+    pub command: String,
+    pub quiet: Option<u64>,
+    pub timeout: Option<u64>,
+    pub terminator: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitReason {
+    Quiet,
+    Terminator,
+    Timeout,
+    Rejected,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandReply {
+    pub command: String,
+    pub lines: Vec<String>,
+    pub reason: ExitReason,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
+
 nest! {
     #[derive(Default, Clone, Deserialize)]
     pub struct ConfigEcon<'a> {
         logging: Option<String>,
         pub nats: NatsConfig<'a>,
 
-        pub econ:
+        /// Single server, kept for configs written before `servers` existed
+        #[serde(default)]
+        pub econ: Option<
             #[derive(Default, Clone, Deserialize)]
             pub struct EconConfig {
+                #[serde(default)]
                 pub host: String,
+                #[serde(default)]
                 pub password: String,
                 #[serde(default = "default_auth_message")]
                 pub auth_message: String,
                 #[serde(default)]
-                pub first_commands: Vec<CowStr<'static>>,
+                pub profile: LogProfile,
+                /// Sent on every (re)connect
+                #[serde(default)]
+                pub first_commands: Vec<CommandTemplate>,
+                /// Run on every (re)connect, publishes `server.up`
+                #[serde(default)]
+                pub on_connect: HookConfig,
+                /// Publishes `server.down` when econ is lost, commands run once it is back
+                #[serde(default)]
+                pub on_disconnect: HookConfig,
+                /// Server log file read instead of the econ output
+                #[serde(default)]
+                pub log: Option<LogSourceConfig>,
+                /// Server started by the bridge, its stdout read instead of the econ output
+                #[serde(default)]
+                pub process: Option<ProcessConfig>,
+                #[serde(default)]
+                pub tasks: Vec<NamedTask>,
+                /// Runtime control of tasks over NATS
+                #[serde(default)]
+                pub control:
+                    #[derive(Clone, Deserialize)]
+                    pub struct ControlConfig {
+                        #[serde(default)]
+                        pub enabled: bool,
+                        #[serde(default = "default_control_subject")]
+                        pub subject: CowStr<'static>,
+                        /// Key-value bucket keeping tasks changed at runtime across restarts
+                        #[serde(default)]
+                        pub kv_bucket: Option<String>,
+                        #[serde(default = "default_kv_key")]
+                        pub kv_key: CowStr<'static>,
+                    },
+                /// Include/exclude rules for lines published to NATS
+                #[serde(default)]
+                pub filter: FilterConfig,
+                #[serde(default)]
+                pub events:
+                    #[derive(Clone, Deserialize)]
+                    pub struct EventsConfig {
+                        #[serde(default)]
+                        pub enabled: bool,
+                        #[serde(default = "default_events_subject")]
+                        pub subject: CowStr<'static>,
+                    },
+                #[serde(default)]
+                pub requests:
+                    #[derive(Clone, Deserialize)]
+                    pub struct RequestsConfig {
+                        #[serde(default)]
+                        pub enabled: bool,
+                        #[serde(default = "default_requests_subject")]
+                        pub subject: CowStr<'static>,
+                        /// Milliseconds without new lines ending the output, counted from its first line
+                        #[serde(default = "default_requests_quiet")]
+                        pub quiet: u64,
+                        /// Upper bound in milliseconds for collecting the output
+                        #[serde(default = "default_requests_timeout")]
+                        pub timeout: u64,
+                        /// Regex matching the last line of the output
+                        #[serde(default)]
+                        pub terminator: Option<String>,
+                    },
+                /// Players on the server, used by task conditions
+                #[serde(default)]
+                pub roster:
+                    #[derive(Clone, Deserialize)]
+                    pub struct RosterConfig {
+                        /// Seconds between `status` requests reconciling the roster, 0 - only after connecting
+                        #[serde(default)]
+                        pub status_interval: u64,
+                        /// Subject for join/leave/update events of the roster
+                        #[serde(default)]
+                        pub subject: Option<CowStr<'static>>,
+                        /// Key-value bucket keeping the current players of every server
+                        #[serde(default)]
+                        pub kv_bucket: Option<String>,
+                        #[serde(default = "default_kv_key")]
+                        pub kv_key: CowStr<'static>,
+                    },
+                #[serde(default)]
+                pub policy: PolicyConfig,
+                /// Lanes of commands waiting for econ, by source subject and command
+                #[serde(default)]
+                pub priority: PriorityConfig,
+                #[serde(default)]
+                pub rate_limit: RateLimitConfig,
+                #[serde(default)]
+                pub chunking: ChunkConfig,
                 #[serde(default)]
-                pub tasks: Vec<Task>,
+                pub spool:
+                    #[derive(Clone, Deserialize)]
+                    pub struct SpoolConfig {
+                        /// File keeping commands across outages and restarts, memory only if unset
+                        #[serde(default)]
+                        pub path: Option<CowStr<'static>>,
+                        /// 0 - unlimited
+                        #[serde(default = "default_spool_max_commands")]
+                        pub max_commands: usize,
+                        /// Seconds a command stays valid, 0 - forever
+                        #[serde(default = "default_spool_expire")]
+                        pub expire: u64,
+                    },
                 #[serde(default)]
                 pub reconnect:
                     #[derive(Clone, Deserialize)]
                     pub struct ReconnectConfig {
+                        /// Pending commands are dropped after this many failed attempts
                         pub max_attempts: i64,
+                        /// Initial delay in seconds, doubled after every failed attempt
                         pub sleep: u64,
+                        #[serde(default = "default_reconnect_max_sleep")]
+                        pub max_sleep: u64,
                     },
-            },
+            }>,
+
+        #[serde(default)]
+        pub servers: Vec<
+            #[derive(Default, Clone, Deserialize)]
+            pub struct ServerConfig<'b> {
+                pub econ: EconConfig,
+                pub from: Option<Vec<CowStr<'b>>>,
+                pub to: Option<Vec<CowStr<'b>>>,
+                pub queue: Option<CowStr<'b>>,
+                pub args: Option<Value>,
+            } ||<'a>>,
 
         pub args: Option<Value>,
     }
 }
 
+impl<'a> ConfigEcon<'a> {
+    /// All configured servers, global `nats` subjects and `args` are used as defaults.
+    pub fn servers(&self) -> Vec<ServerConfig<'a>> {
+        let global = ServerConfig {
+            econ: EconConfig::default(),
+            from: self.nats.from.clone(),
+            to: self.nats.to.clone(),
+            queue: self.nats.queue.clone(),
+            args: self.args.clone(),
+        };
+
+        let mut servers = Vec::new();
+        if let Some(econ) = &self.econ {
+            servers.push(ServerConfig {
+                econ: econ.clone(),
+                ..global.clone()
+            });
+        }
+        for server in &self.servers {
+            let args = Args::merge_yaml_values(
+                &global.args.clone().unwrap_or_default(),
+                &server.args.clone().unwrap_or_default(),
+            );
+            servers.push(ServerConfig {
+                econ: server.econ.clone(),
+                from: server.from.clone().or_else(|| global.from.clone()),
+                to: server.to.clone().or_else(|| global.to.clone()),
+                queue: server.queue.clone().or_else(|| global.queue.clone()),
+                args: Some(args),
+            });
+        }
+        servers
+    }
+}
+
+impl ServerConfig<'_> {
+    /// Name used in logs: `args.server_name` or the econ host
+    pub fn name(&self) -> String {
+        let args = self.args.clone().unwrap_or_default();
+        let name = Args::get(&args, "server_name", String::new());
+        if name.is_empty() {
+            self.econ.host.clone()
+        } else {
+            name
+        }
+    }
+}
+
 impl BaseConfig for ConfigEcon<'_> {
     fn nats_config(&self) -> &NatsConfig<'_> {
         &self.nats
@@ -66,13 +281,6 @@ impl BaseConfig for ConfigEcon<'_> {
     async fn default_config() -> &'static str {
         include_str!("../default_config/econ.yaml")
     }
-
-    async fn econ_connect(&self) -> anyhow::Result<Econ> {
-        self.econ
-            .clone()
-            .econ_connect(Option::from(&self.args))
-            .await
-    }
 }
 
 impl EconConfig {
@@ -85,83 +293,185 @@ impl EconConfig {
             .ok_or_else(|| anyhow!("No socket address resolved from '{addr}'"))
     }
 
-    pub async fn econ_connect(&self, args: Option<&Value>) -> anyhow::Result<Econ> {
-        let mut econ = Econ::new();
-        let max_attempts = 3;
-        let mut connection_error: Option<anyhow::Error> = None;
-
-        for attempt in 1..=max_attempts {
-            let addr = match self.get_econ_addr(args) {
-                Ok(a) => a,
-                Err(e) => {
-                    connection_error = Some(anyhow!("econ.get_econ_addr, err: {e}"));
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    continue;
-                }
-            };
-            match econ.connect(addr).await {
-                Ok(()) => {
-                    econ.set_auth_message(self.auth_message.clone());
-
-                    match econ.try_auth(&self.password).await {
-                        Ok(true) => return Ok(econ),
-                        Ok(false) => return Err(anyhow!("Econ client is not authorized")),
-                        Err(err) => {
-                            connection_error = Some(anyhow!("econ.try_auth, err: {err}"));
-                        }
-                    }
-                }
-                Err(err) => {
-                    connection_error = Some(anyhow!(
-                        "Attempt {attempt}: econ.connect failed, err: {err}",
-                    ));
-                }
-            }
-            if attempt < max_attempts {
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    /// Checks values serde accepts but the pipeline can't run with.
+    pub fn validate(&self, args: &Value) -> anyhow::Result<()> {
+        self.rate_limit.validate()?;
+        let mut rendered = Vec::new();
+        if self.control.enabled {
+            rendered.push(("control.subject", &self.control.subject));
+        }
+        if self.control.kv_bucket.is_some() {
+            rendered.push(("control.kv_key", &self.control.kv_key));
+        }
+        if self.requests.enabled {
+            rendered.push(("requests.subject", &self.requests.subject));
+        }
+        if let Some(subject) = &self.roster.subject {
+            rendered.push(("roster.subject", subject));
+        }
+        if self.roster.kv_bucket.is_some() {
+            rendered.push(("roster.kv_key", &self.roster.kv_key));
+        }
+        if let Some(process) = self.process.as_ref().filter(|p| p.control.enabled) {
+            rendered.push(("process.control.subject", &process.control.subject));
+        }
+        for (name, template) in rendered {
+            let value = formatting::get_and_format(template, args, &[] as &[&str]);
+            if value.is_empty() || value.ends_with('.') {
+                return Err(anyhow!(
+                    "{name} `{template}` renders to `{value}`, set the args it uses"
+                ));
             }
         }
+        for (index, task) in self.tasks.iter().enumerate() {
+            let name = task
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("#{}", index + 1));
+            task.task
+                .validate()
+                .map_err(|e| anyhow!("task {name}: {e}"))?;
+        }
+        Ok(())
+    }
 
-        Err(connection_error
-            .unwrap_or_else(|| anyhow!("Failed to connect after {max_attempts} attempts")))
+    /// `false` when the lines come from a log file or process configured without econ.
+    pub fn sends_commands(&self) -> bool {
+        match (&self.process, &self.log) {
+            (Some(process), _) => process.commands,
+            (None, Some(log)) => log.commands,
+            (None, None) => true,
+        }
     }
 }
 
-impl Default for ReconnectConfig {
+impl Default for RosterConfig {
     fn default() -> Self {
         Self {
-            max_attempts: 20,
-            sleep: 10,
+            status_interval: 0,
+            subject: None,
+            kv_bucket: None,
+            kv_key: default_kv_key(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LineState {
-    #[serde(skip_serializing, skip_deserializing)]
-    pub index: Arc<AtomicUsize>,
-    pub commands: Vec<String>,
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: default_control_subject(),
+            kv_bucket: None,
+            kv_key: default_kv_key(),
+        }
+    }
+}
+
+impl Default for RequestsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: default_requests_subject(),
+            quiet: default_requests_quiet(),
+            timeout: default_requests_timeout(),
+            terminator: None,
+        }
+    }
 }
 
-impl LineState {
-    pub fn new(commands: Vec<String>) -> Self {
+impl Default for SpoolConfig {
+    fn default() -> Self {
         Self {
-            index: Arc::new(AtomicUsize::new(0)),
-            commands,
+            path: None,
+            max_commands: default_spool_max_commands(),
+            expire: default_spool_expire(),
         }
     }
+}
 
-    pub fn get_next_command(&self) -> String {
-        if self.commands.is_empty() {
-            warn!("get_next_command: No commands available");
-            return String::default();
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 20,
+            sleep: 10,
+            max_sleep: default_reconnect_max_sleep(),
         }
+    }
+}
 
-        let index = self.index.fetch_add(1, Ordering::Relaxed);
-        self.commands[index % self.commands.len()].clone()
+impl ReconnectConfig {
+    pub fn backoff(&self, attempt: i64) -> Duration {
+        let exp = u32::try_from(attempt.saturating_sub(1).clamp(0, 16)).unwrap_or(16);
+        Duration::from_secs(self.sleep.saturating_mul(1 << exp).min(self.max_sleep))
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: default_events_subject(),
+        }
     }
 }
 
 fn default_auth_message() -> String {
     "Authentication successful".to_string()
 }
+
+fn default_spool_max_commands() -> usize {
+    1000
+}
+
+fn default_spool_expire() -> u64 {
+    600
+}
+
+fn default_reconnect_max_sleep() -> u64 {
+    300
+}
+
+fn default_control_subject() -> CowStr<'static> {
+    CowStr::Borrowed("tw.econ.tasks.{{server_name}}")
+}
+
+fn default_kv_key() -> CowStr<'static> {
+    CowStr::Borrowed("{{server_name}}")
+}
+
+fn default_requests_subject() -> CowStr<'static> {
+    CowStr::Borrowed("tw.econ.request.{{server_name}}")
+}
+
+fn default_requests_quiet() -> u64 {
+    500
+}
+
+fn default_requests_timeout() -> u64 {
+    5000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_rendered_subjects() {
+        let args: Value = serde_yaml::from_str("server_name: test").unwrap();
+        for yaml in [
+            "control: {enabled: true}",
+            "control: {kv_bucket: tasks}",
+            "requests: {enabled: true}",
+            "roster: {subject: 'tw.roster.{{server_name}}', kv_bucket: roster}",
+            "process: {path: ./server, control: {enabled: true}}",
+        ] {
+            let config: EconConfig = serde_yaml::from_str(yaml).unwrap();
+            assert!(config.validate(&args).is_ok(), "{yaml}");
+            let error = config.validate(&Value::Null).unwrap_err().to_string();
+            assert!(error.contains("set the args it uses"), "{yaml}: {error}");
+        }
+        // Unused subjects are not rendered
+        let config: EconConfig = serde_yaml::from_str("roster: {status_interval: 10}").unwrap();
+        assert!(config.validate(&Value::Null).is_ok());
+    }
+}
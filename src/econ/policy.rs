@@ -0,0 +1,284 @@
+use crate::model::CowStr;
+use crate::nats::{subject_matches, Nats};
+use anyhow::anyhow;
+use bytes::Bytes;
+use log::{trace, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct CommandRule {
+    /// Command name, `*` matches any command
+    pub command: String,
+    /// Regex matched against the arguments of the command
+    #[serde(default)]
+    pub args: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct PolicyRules {
+    /// NATS subject patterns (`*`, `>`) the rules apply to, empty - every subject
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<CommandRule>,
+    #[serde(default)]
+    pub deny: Vec<CommandRule>,
+    /// Action for commands matching no rule, `deny` if `allow` is set, otherwise `allow`
+    #[serde(default)]
+    pub default: Option<PolicyAction>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct PolicyConfig {
+    /// Subject receiving a report for every rejected command
+    #[serde(default)]
+    pub rejected: Option<CowStr<'static>>,
+    #[serde(default)]
+    pub rules: Vec<PolicyRules>,
+}
+
+struct CompiledRule {
+    command: String,
+    args: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn new(rule: &CommandRule) -> anyhow::Result<Self> {
+        let args = match &rule.args {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| anyhow!("Invalid policy args regex \"{pattern}\": {e}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            command: rule.command.to_lowercase(),
+            args,
+        })
+    }
+
+    fn matches(&self, name: &str, args: &str) -> bool {
+        (self.command == "*" || self.command == name)
+            && self.args.as_ref().is_none_or(|re| re.is_match(args))
+    }
+}
+
+struct CompiledRules {
+    subjects: Vec<String>,
+    allow: Vec<CompiledRule>,
+    deny: Vec<CompiledRule>,
+    default: PolicyAction,
+}
+
+/// Allow/deny rules applied to commands arriving from NATS.
+pub struct CommandPolicy {
+    rejected: Option<CowStr<'static>>,
+    rules: Vec<CompiledRules>,
+}
+
+#[derive(Debug, Serialize)]
+struct RejectedCommand<'a> {
+    subject: &'a str,
+    command: &'a str,
+    reason: &'a str,
+}
+
+impl CommandPolicy {
+    pub fn new(config: &PolicyConfig, rejected: Option<CowStr<'static>>) -> anyhow::Result<Self> {
+        let mut rules = Vec::with_capacity(config.rules.len());
+        for rule in &config.rules {
+            rules.push(CompiledRules {
+                subjects: rule.subjects.clone(),
+                allow: rule
+                    .allow
+                    .iter()
+                    .map(CompiledRule::new)
+                    .collect::<anyhow::Result<_>>()?,
+                deny: rule
+                    .deny
+                    .iter()
+                    .map(CompiledRule::new)
+                    .collect::<anyhow::Result<_>>()?,
+                default: rule.default.unwrap_or(if rule.allow.is_empty() {
+                    PolicyAction::Allow
+                } else {
+                    PolicyAction::Deny
+                }),
+            });
+        }
+        Ok(Self { rejected, rules })
+    }
+
+    /// Checks every `;` separated statement of `command`, the first matching rule set wins.
+    pub fn check(&self, subject: &str, command: &str) -> Result<(), String> {
+        let Some(rules) = self.rules.iter().find(|rules| {
+            rules.subjects.is_empty()
+                || rules
+                    .subjects
+                    .iter()
+                    .any(|pattern| subject_matches(pattern, subject))
+        }) else {
+            return Ok(());
+        };
+
+        for statement in split_statements(command) {
+            let (name, args) = match statement.split_once(char::is_whitespace) {
+                Some((name, args)) => (name.to_lowercase(), args.trim()),
+                None => (statement.to_lowercase(), ""),
+            };
+
+            if rules.deny.iter().any(|rule| rule.matches(&name, args)) {
+                return Err(format!("command \"{name}\" is denied"));
+            }
+            if rules.allow.iter().any(|rule| rule.matches(&name, args)) {
+                continue;
+            }
+            if rules.default == PolicyAction::Deny {
+                return Err(format!("command \"{name}\" is not allowed"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs a rejected command and reports it on the `rejected` subject.
+    pub async fn reject(&self, nats: &Nats, subject: &str, command: &str, reason: &str) {
+        warn!("Rejected command from {subject}: {reason}, command: \"{command}\"");
+        let Some(rejected) = &self.rejected else {
+            return;
+        };
+        let json = match serde_json::to_string_pretty(&RejectedCommand {
+            subject,
+            command,
+            reason,
+        }) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("Error converting rejected command to json: {err}");
+                return;
+            }
+        };
+        trace!("Sending rejected command to {rejected}");
+        nats.publish_bytes(rejected.clone(), Bytes::from(json))
+            .await
+            .ok();
+    }
+}
+
+/// Splits a console line into statements on `;` outside of quotes.
+pub fn split_statements(line: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (idx, ch) in line.char_indices() {
+        match ch {
+            // econ is line based, a line break always starts a new command
+            '\n' | '\r' => {
+                statements.push(&line[start..idx]);
+                start = idx + ch.len_utf8();
+                in_quotes = false;
+                escaped = false;
+            }
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                statements.push(&line[start..idx]);
+                start = idx + ch.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    statements.push(&line[start..]);
+
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::econ::model::ConfigEcon;
+
+    fn policy(yaml: &str) -> CommandPolicy {
+        let config: PolicyConfig = serde_yaml::from_str(yaml).unwrap();
+        CommandPolicy::new(&config, None).unwrap()
+    }
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(split_statements("say hi"), vec!["say hi"]);
+        assert_eq!(
+            split_statements("say \"a; b\"; shutdown"),
+            vec!["say \"a; b\"", "shutdown"]
+        );
+        assert_eq!(
+            split_statements(r#"say "quote \"; still"; kick 1"#),
+            vec![r#"say "quote \"; still""#, "kick 1"]
+        );
+        assert_eq!(
+            split_statements("say \"a\nshutdown"),
+            vec!["say \"a", "shutdown"]
+        );
+        assert_eq!(
+            split_statements("say \"a\\\nkick 1"),
+            vec!["say \"a\\", "kick 1"]
+        );
+    }
+
+    #[test]
+    fn test_deny() {
+        let policy =
+            policy("rules:\n  - deny:\n      - command: shutdown\n      - command: exec\n");
+        assert!(policy.check("tw.econ.write.1", "say hello").is_ok());
+        assert!(policy.check("tw.econ.write.1", "SHUTDOWN").is_err());
+        assert!(policy
+            .check("tw.econ.write.1", "say hi; exec x.cfg")
+            .is_err());
+    }
+
+    #[test]
+    fn test_allow_per_subject() {
+        let policy = policy(
+            r#"
+rules:
+  - subjects: ["tw.econ.moderator"]
+  - subjects: ["tw.econ.write.*"]
+    allow:
+      - command: say
+      - command: kick
+        args: "^\\d+$"
+"#,
+        );
+        assert!(policy.check("tw.econ.moderator", "shutdown").is_ok());
+        assert!(policy.check("tw.econ.write.5", "say \"x\"").is_ok());
+        assert!(policy.check("tw.econ.write.5", "kick 3").is_ok());
+        assert!(policy.check("tw.econ.write.5", "kick 3 reason").is_err());
+        assert!(policy.check("tw.econ.write.5", "ban 3").is_err());
+        assert!(policy.check("tw.other", "ban 3").is_ok());
+    }
+
+    #[test]
+    fn test_default_config_rules() {
+        let config: ConfigEcon =
+            serde_yaml::from_str(include_str!("../default_config/econ.yaml")).unwrap();
+        let server = &config.servers()[0];
+        let policy = CommandPolicy::new(&server.econ.policy, None).unwrap();
+        assert!(policy.check("tw.ddnet.write.x", "shutdown").is_err());
+        assert!(policy.check("tw.sync", "exec evil.cfg").is_err());
+        assert!(policy.check("tw.econ.tasks.server-1", "shutdown").is_err());
+        assert!(policy.check("tw.ddnet.write.x", "say hi").is_ok());
+    }
+}
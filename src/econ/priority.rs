@@ -0,0 +1,81 @@
+use crate::nats::subject_matches;
+use serde::{Deserialize, Serialize};
+
+/// Lane of a command waiting for econ.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Dropped first when the spool is full
+    Low,
+    #[default]
+    Normal,
+    /// Sent before every other command, never rate limited
+    High,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct PriorityRule {
+    /// NATS subject patterns (`*`, `>`), tasks use the `task` source, empty - every source
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    /// Command names, empty - every command
+    #[serde(default)]
+    pub commands: Vec<String>,
+    pub priority: Priority,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub struct PriorityConfig {
+    /// The first matching rule wins, commands matching none are `normal`
+    #[serde(default)]
+    pub rules: Vec<PriorityRule>,
+}
+
+impl PriorityConfig {
+    pub fn classify(&self, source: &str, command: &str) -> Priority {
+        let name = command
+            .split(|c: char| c.is_whitespace() || c == ';')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        self.rules
+            .iter()
+            .find(|rule| {
+                (rule.subjects.is_empty()
+                    || rule
+                        .subjects
+                        .iter()
+                        .any(|pattern| subject_matches(pattern, source)))
+                    && (rule.commands.is_empty()
+                        || rule.commands.iter().any(|c| c.eq_ignore_ascii_case(&name)))
+            })
+            .map_or(Priority::Normal, |rule| rule.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let config: PriorityConfig = serde_yaml::from_str(
+            "rules:\n\
+             \x20 - {subjects: [tw.econ.moderator], priority: high}\n\
+             \x20 - {commands: [kick, ban], priority: high}\n\
+             \x20 - {subjects: [task], commands: [say], priority: low}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.classify("tw.econ.moderator", "say hi"),
+            Priority::High
+        );
+        assert_eq!(config.classify("tw.econ.write.1", "KICK 3"), Priority::High);
+        assert_eq!(config.classify("task", "say \"hi\""), Priority::Low);
+        assert_eq!(config.classify("task", "status"), Priority::Normal);
+        assert_eq!(
+            config.classify("tw.econ.write.1", "say hi"),
+            Priority::Normal
+        );
+    }
+}
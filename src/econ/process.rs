@@ -0,0 +1,488 @@
+use crate::model::CowStr;
+use crate::nats::Nats;
+use crate::util::convert;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{sleep, timeout, Instant};
+
+/// Lines kept while no reader is running, the server never blocks on its stdout
+const OUTPUT_BUFFER: usize = 4096;
+
+/// Game server started and restarted by the bridge, its stdout replaces the econ output.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessConfig {
+    /// Server binary
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Seconds between SIGTERM and SIGKILL when stopping
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    #[serde(default)]
+    pub control: ProcessControlConfig,
+    /// Send commands over econ, `false` - no econ connection at all
+    #[serde(default = "default_commands")]
+    pub commands: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestartConfig {
+    /// Initial delay in seconds, doubled after every crash
+    #[serde(default = "default_restart_sleep")]
+    pub sleep: u64,
+    #[serde(default = "default_restart_max_sleep")]
+    pub max_sleep: u64,
+    /// Seconds of uptime after which the delay starts over
+    #[serde(default = "default_restart_reset_after")]
+    pub reset_after: u64,
+}
+
+/// `start`/`stop`/`restart`/`status` requests over NATS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessControlConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_control_subject")]
+    pub subject: CowStr<'static>,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            sleep: default_restart_sleep(),
+            max_sleep: default_restart_max_sleep(),
+            reset_after: default_restart_reset_after(),
+        }
+    }
+}
+
+impl Default for ProcessControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subject: default_control_subject(),
+        }
+    }
+}
+
+impl RestartConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16);
+        Duration::from_secs(self.sleep.saturating_mul(1 << exp).min(self.max_sleep))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessAction {
+    Start,
+    Stop,
+    Restart,
+    Status,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProcessRequest {
+    action: ProcessAction,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ProcessStatus {
+    pub running: bool,
+    pub pid: Option<u32>,
+    /// Unix time in seconds
+    pub started_at: Option<i64>,
+    /// Restarts after the server exited on its own
+    pub crashes: u32,
+    pub last_exit: Option<String>,
+}
+
+type ControlRequest = (ProcessAction, oneshot::Sender<ProcessStatus>);
+
+/// Stdout of the server, shared by the readers of the pipeline.
+#[derive(Clone)]
+pub struct ProcessOutput {
+    pub lines: Arc<Mutex<mpsc::Receiver<String>>>,
+}
+
+/// Control of a supervised server, dropping every handle stops it.
+#[derive(Clone)]
+pub struct ProcessHandle {
+    control: mpsc::Sender<ControlRequest>,
+}
+
+impl ProcessHandle {
+    pub async fn request(&self, action: ProcessAction) -> Option<ProcessStatus> {
+        let (tx, rx) = oneshot::channel();
+        self.control.send((action, tx)).await.ok()?;
+        rx.await.ok()
+    }
+}
+
+/// Starts the server and keeps it running until stopped.
+pub fn spawn_process(config: ProcessConfig, name: String) -> (ProcessOutput, ProcessHandle) {
+    let (lines_tx, lines_rx) = mpsc::channel(OUTPUT_BUFFER);
+    let (control_tx, control_rx) = mpsc::channel(8);
+    let supervisor = Supervisor {
+        config,
+        name,
+        lines: lines_tx,
+        control: control_rx,
+        status: ProcessStatus::default(),
+        pending: Vec::new(),
+    };
+    tokio::spawn(supervisor.run());
+    (
+        ProcessOutput {
+            lines: Arc::new(Mutex::new(lines_rx)),
+        },
+        ProcessHandle {
+            control: control_tx,
+        },
+    )
+}
+
+struct Supervisor {
+    config: ProcessConfig,
+    name: String,
+    lines: mpsc::Sender<String>,
+    control: mpsc::Receiver<ControlRequest>,
+    status: ProcessStatus,
+    /// `start`/`restart` requests answered once the server runs again
+    pending: Vec<oneshot::Sender<ProcessStatus>>,
+}
+
+/// What the supervisor does after the server stopped.
+enum Next {
+    Start,
+    Wait,
+    Exit,
+}
+
+impl Supervisor {
+    async fn run(mut self) {
+        let name = self.name.clone();
+        let mut next = Next::Start;
+        let mut attempt = 0;
+        loop {
+            match next {
+                Next::Exit => return,
+                Next::Wait => {
+                    next = self.wait_for_start().await;
+                    attempt = 0;
+                    continue;
+                }
+                Next::Start => {}
+            }
+
+            let mut child = match start(&self.config, &self.lines) {
+                Ok(child) => child,
+                Err(err) => {
+                    error!("[{name}] Failed to start server: {err}");
+                    self.status.last_exit = Some(err.to_string());
+                    attempt += 1;
+                    next = self.backoff(attempt).await;
+                    continue;
+                }
+            };
+            let started = Instant::now();
+            self.status.running = true;
+            self.status.pid = child.id();
+            self.status.started_at = Some(chrono::Utc::now().timestamp());
+            for reply in self.pending.drain(..) {
+                reply.send(self.status.clone()).ok();
+            }
+            info!("[{name}] Server started, pid {:?}", self.status.pid);
+
+            let exit = loop {
+                tokio::select! {
+                    exit = child.wait() => break exit.ok(),
+                    request = self.control.recv() => {
+                        let Some((action, reply)) = request else {
+                            stop(&mut child, self.config.stop_timeout).await;
+                            return;
+                        };
+                        if matches!(action, ProcessAction::Start | ProcessAction::Status) {
+                            reply.send(self.status.clone()).ok();
+                            continue;
+                        }
+                        info!("[{name}] Server {action:?} requested");
+                        let exit = stop(&mut child, self.config.stop_timeout).await;
+                        self.stopped(exit);
+                        if action == ProcessAction::Restart {
+                            self.pending.push(reply);
+                            next = Next::Start;
+                        } else {
+                            reply.send(self.status.clone()).ok();
+                            next = Next::Wait;
+                        }
+                        attempt = 0;
+                        break None;
+                    }
+                }
+            };
+            let Some(exit) = exit else {
+                continue;
+            };
+
+            warn!("[{name}] Server exited: {exit}");
+            self.stopped(Some(exit));
+            self.status.crashes += 1;
+            if started.elapsed() >= Duration::from_secs(self.config.restart.reset_after) {
+                attempt = 0;
+            }
+            attempt += 1;
+            next = self.backoff(attempt).await;
+        }
+    }
+
+    fn stopped(&mut self, exit: Option<ExitStatus>) {
+        self.status.running = false;
+        self.status.pid = None;
+        self.status.last_exit = exit.map(|exit| exit.to_string());
+    }
+
+    /// Stopped on request, only `start` or `restart` bring the server back.
+    async fn wait_for_start(&mut self) -> Next {
+        loop {
+            let Some((action, reply)) = self.control.recv().await else {
+                return Next::Exit;
+            };
+            if matches!(action, ProcessAction::Start | ProcessAction::Restart) {
+                self.pending.push(reply);
+                return Next::Start;
+            }
+            reply.send(self.status.clone()).ok();
+        }
+    }
+
+    /// Delay before restarting a crashed server, requests are answered meanwhile.
+    async fn backoff(&mut self, attempt: u32) -> Next {
+        let delay = self.config.restart.backoff(attempt);
+        debug!("[{}] Restarting server in {delay:?}", self.name);
+        let deadline = sleep(delay);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                () = &mut deadline => return Next::Start,
+                request = self.control.recv() => {
+                    let Some((action, reply)) = request else {
+                        return Next::Exit;
+                    };
+                    match action {
+                        ProcessAction::Status => {
+                            reply.send(self.status.clone()).ok();
+                        }
+                        ProcessAction::Stop => {
+                            reply.send(self.status.clone()).ok();
+                            return Next::Wait;
+                        }
+                        ProcessAction::Start | ProcessAction::Restart => {
+                            self.pending.push(reply);
+                            return Next::Start;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn start(config: &ProcessConfig, lines: &mpsc::Sender<String>) -> std::io::Result<Child> {
+    let mut command = Command::new(&config.path);
+    command
+        .args(&config.args)
+        .envs(&config.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(cwd) = &config.cwd {
+        command.current_dir(cwd);
+    }
+    let mut child = command.spawn()?;
+
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(read_output(stdout, lines.clone()));
+    }
+    Ok(child)
+}
+
+/// Forwards stdout line by line, invalid UTF-8 is replaced instead of ending the output.
+async fn read_output(stdout: ChildStdout, lines: mpsc::Sender<String>) {
+    let mut reader = BufReader::new(stdout);
+    let mut buffer = Vec::new();
+    let mut dropped = 0u64;
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) => {
+                warn!("Server output closed");
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Server output read failed: {err}");
+                return;
+            }
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(['\n', '\r']).to_string();
+        if lines.try_send(line).is_err() {
+            dropped += 1;
+            if dropped.is_power_of_two() {
+                warn!("Server output not read, {dropped} lines dropped");
+            }
+        }
+    }
+}
+
+/// SIGTERM lets the server save and say goodbye, SIGKILL after `stop_timeout`.
+async fn stop(child: &mut Child, stop_timeout: u64) -> Option<ExitStatus> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id().and_then(|pid| i32::try_from(pid).ok()) {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        if kill(Pid::from_raw(pid), Signal::SIGTERM).is_ok() {
+            if let Ok(exit) = timeout(Duration::from_secs(stop_timeout), child.wait()).await {
+                return exit.ok();
+            }
+            warn!("Server did not stop in {stop_timeout}s, killing it");
+        }
+    }
+    child.kill().await.ok();
+    child.wait().await.ok()
+}
+
+/// Request/reply control of the server, the payload is `{"action": "restart"}` or just `restart`.
+pub async fn process_control(nats: Nats, subject: CowStr<'static>, handle: ProcessHandle) {
+    info!("Subscribe to the process control channel: {subject}");
+    let mut subscriber = nats.subscriber(subject, CowStr::Borrowed("")).await;
+
+    while let Some(message) = subscriber.next().await {
+        let action = convert::<ProcessRequest>(&message.payload)
+            .map(|request| request.action)
+            .or_else(|| {
+                let text = String::from_utf8_lossy(&message.payload);
+                serde_yaml::from_str(text.trim()).ok()
+            });
+        let Some(action) = action else {
+            warn!("Invalid process control request from {}", message.subject);
+            continue;
+        };
+        let Some(status) = handle.request(action).await else {
+            error!("Process supervisor is gone");
+            return;
+        };
+        let Some(reply) = message.reply else {
+            continue;
+        };
+        match serde_json::to_string_pretty(&status) {
+            Ok(json) => {
+                if let Err(err) = nats.nats.publish(reply, Bytes::from(json)).await {
+                    warn!("Failed to reply to process control request: {err}");
+                }
+            }
+            Err(err) => warn!("Error converting process status to json: {err}"),
+        }
+    }
+}
+
+fn default_stop_timeout() -> u64 {
+    10
+}
+
+fn default_commands() -> bool {
+    true
+}
+
+fn default_restart_sleep() -> u64 {
+    1
+}
+
+fn default_restart_max_sleep() -> u64 {
+    60
+}
+
+fn default_restart_reset_after() -> u64 {
+    60
+}
+
+fn default_control_subject() -> CowStr<'static> {
+    CowStr::Borrowed("tw.econ.process.{{server_name}}")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn config(script: &str) -> ProcessConfig {
+        serde_yaml::from_str(&format!(
+            "{{path: sh, args: [-c, '{script}'], restart: {{sleep: 0}}, stop_timeout: 1}}"
+        ))
+        .unwrap()
+    }
+
+    async fn next_line(output: &ProcessOutput) -> String {
+        let mut lines = output.lines.lock().await;
+        timeout(Duration::from_secs(5), lines.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_control() {
+        let (output, handle) =
+            spawn_process(config("echo started; exec sleep 30"), "test".to_string());
+        assert_eq!(next_line(&output).await, "started");
+        let first = handle.request(ProcessAction::Status).await.unwrap();
+        assert!(first.running);
+
+        let restarted = handle.request(ProcessAction::Restart).await.unwrap();
+        assert!(restarted.running);
+        assert_ne!(restarted.pid, first.pid);
+        assert_eq!(next_line(&output).await, "started");
+
+        let stopped = handle.request(ProcessAction::Stop).await.unwrap();
+        assert!(!stopped.running);
+        assert_eq!(stopped.crashes, 0);
+        let started = handle.request(ProcessAction::Start).await.unwrap();
+        assert!(started.running);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let (output, handle) = spawn_process(
+            config(r#"printf "a\377b\r\nnext\n"; exec sleep 30"#),
+            "test".to_string(),
+        );
+        assert_eq!(next_line(&output).await, "a\u{FFFD}b");
+        assert_eq!(next_line(&output).await, "next");
+        handle.request(ProcessAction::Stop).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_on_crash() {
+        let (output, handle) = spawn_process(config("echo up; exit 3"), "test".to_string());
+        assert_eq!(next_line(&output).await, "up");
+        assert_eq!(next_line(&output).await, "up");
+        let status = handle.request(ProcessAction::Status).await.unwrap();
+        assert!(status.crashes >= 1);
+        assert!(status.last_exit.unwrap().contains('3'));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::util::hardcoded_regex as re;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+static DDNET_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(
+        r"^(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}) (?P<level>[A-Z]) (?P<system>[^:]+): (?P<message>.*)$",
+    )
+});
+static TEEWORLDS06_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(r"^\[(?P<timestamp>[0-9a-fA-F]{8})\]\[(?P<system>[^\]]+)\]: (?P<message>.*)$")
+});
+static TEEWORLDS07_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(
+        r"^\[(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2})\]\[(?P<system>[^\]]+)\]: (?P<message>.*)$",
+    )
+});
+static INFCLASS_RE: LazyLock<Regex> = LazyLock::new(|| {
+    re(r"^(?:\[(?P<timestamp>[^\]]+)\])?\[(?P<system>[^\]]+)\]: (?P<message>.*)$")
+});
+
+/// Log line grammar used by the econ server.
+///
+/// - `ddnet`: `2024-05-01 12:00:00 I chat: ...`
+/// - `teeworlds06`: `[663221f0][chat]: ...` (hex unix time)
+/// - `teeworlds07`: `[2024-05-01 12:00:00][chat]: ...`
+/// - `infclass`: `[2024-05-01 12:00:00][chat]: ...`, the timestamp is optional
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogProfile {
+    #[default]
+    Ddnet,
+    Teeworlds06,
+    Teeworlds07,
+    Infclass,
+}
+
+/// A single econ line split into the log prefix and the message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine<'a> {
+    pub timestamp: &'a str,
+    /// Log level letter, empty for profiles without levels
+    pub level: &'a str,
+    pub system: &'a str,
+    pub message: &'a str,
+    /// Grammar the line was parsed with, event patterns differ between servers
+    pub profile: LogProfile,
+}
+
+impl LogProfile {
+    fn regex(self) -> &'static Regex {
+        match self {
+            LogProfile::Ddnet => &DDNET_RE,
+            LogProfile::Teeworlds06 => &TEEWORLDS06_RE,
+            LogProfile::Teeworlds07 => &TEEWORLDS07_RE,
+            LogProfile::Infclass => &INFCLASS_RE,
+        }
+    }
+
+    /// Maps mod-specific system names onto the ones used by DDNet.
+    fn system(self, system: &str) -> &str {
+        match (self, system) {
+            (LogProfile::Teeworlds06 | LogProfile::Teeworlds07, "engine/datafile") => "datafile",
+            (LogProfile::Infclass, "game/infclass") => "game",
+            _ => system,
+        }
+    }
+
+    pub fn parse<'a>(self, line: &'a str) -> Option<LogLine<'a>> {
+        let caps = self.regex().captures(line)?;
+        let get = |name: &str| caps.name(name).map_or("", |m| m.as_str());
+
+        Some(LogLine {
+            timestamp: get("timestamp"),
+            level: get("level"),
+            system: self.system(caps.name("system")?.as_str()),
+            message: caps.name("message")?.as_str(),
+            profile: self,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ddnet() {
+        let line = LogProfile::Ddnet
+            .parse("2024-05-01 12:00:00 I chat: 0:-2:nameless tee: hi: there")
+            .unwrap();
+        assert_eq!(
+            line,
+            LogLine {
+                timestamp: "2024-05-01 12:00:00",
+                level: "I",
+                system: "chat",
+                message: "0:-2:nameless tee: hi: there",
+                profile: LogProfile::Ddnet,
+            }
+        );
+        assert!(LogProfile::Ddnet
+            .parse("[2024-05-01 12:00:00][chat]: 0:-2:nameless tee: hi")
+            .is_none());
+    }
+
+    #[test]
+    fn test_teeworlds06() {
+        let line = LogProfile::Teeworlds06
+            .parse("[663221f0][game]: kill killer='0:nameless tee' victim='1:brainless tee' weapon=1 special=0")
+            .unwrap();
+        assert_eq!(line.timestamp, "663221f0");
+        assert_eq!(line.level, "");
+        assert_eq!(line.system, "game");
+        assert_eq!(
+            line.message,
+            "kill killer='0:nameless tee' victim='1:brainless tee' weapon=1 special=0"
+        );
+    }
+
+    #[test]
+    fn test_teeworlds07() {
+        let line = LogProfile::Teeworlds07
+            .parse("[2024-05-01 12:00:00][engine/datafile]: loading done. datafile='maps/dm1.map'")
+            .unwrap();
+        assert_eq!(line.timestamp, "2024-05-01 12:00:00");
+        assert_eq!(line.system, "datafile");
+        assert_eq!(line.message, "loading done. datafile='maps/dm1.map'");
+    }
+
+    #[test]
+    fn test_infclass() {
+        let line = LogProfile::Infclass
+            .parse("[2024-05-01 12:00:00][game/infclass]: leave player='3:Sheep'")
+            .unwrap();
+        assert_eq!(line.system, "game");
+        assert_eq!(line.message, "leave player='3:Sheep'");
+
+        let line = LogProfile::Infclass
+            .parse("[chat]: 1:-2:Sheep: hello")
+            .unwrap();
+        assert_eq!(line.timestamp, "");
+        assert_eq!(line.system, "chat");
+    }
+}
@@ -0,0 +1,455 @@
+use crate::econ::command::{quote_into, unquote};
+use crate::nats::subject_matches;
+use anyhow::anyhow;
+use log::info;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Upper bound of a single wait, the bucket is checked again afterwards
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+/// What happens to a command arriving while its bucket is empty.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitPolicy {
+    /// Wait for the next token
+    #[default]
+    Delay,
+    /// Hold the command and send everything held as one line with the next token
+    Merge,
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitConfig {
+    /// Tokens per second
+    pub rate: f64,
+    #[serde(default = "default_burst")]
+    pub burst: f64,
+    #[serde(default)]
+    pub policy: LimitPolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubjectLimitConfig {
+    /// NATS subject patterns (`*`, `>`), tasks use the `task` source
+    pub subjects: Vec<String>,
+    #[serde(flatten)]
+    pub limit: LimitConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub global: Option<LimitConfig>,
+    #[serde(default)]
+    pub subjects: Vec<SubjectLimitConfig>,
+    /// Seconds between counter reports in the log, 0 - never
+    #[serde(default = "default_report")]
+    pub report: u64,
+}
+
+impl RateLimitConfig {
+    /// Rejects rates a bucket could never refill with.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let limits = self
+            .global
+            .iter()
+            .chain(self.subjects.iter().map(|rule| &rule.limit));
+        for limit in limits {
+            if !(limit.rate > 0.0 && limit.rate.is_finite()) {
+                return Err(anyhow!(
+                    "rate_limit: rate must be a positive number, got {}",
+                    limit.rate
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            global: None,
+            subjects: Vec::new(),
+            report: default_report(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    pub sent: u64,
+    pub delayed: u64,
+    pub merged: u64,
+    pub dropped: u64,
+}
+
+struct Bucket {
+    name: String,
+    subjects: Vec<String>,
+    rate: f64,
+    burst: f64,
+    policy: LimitPolicy,
+    tokens: f64,
+    updated: Instant,
+    counters: Counters,
+    reported: Counters,
+}
+
+impl Bucket {
+    fn new(name: String, subjects: Vec<String>, config: &LimitConfig, now: Instant) -> Self {
+        Self {
+            name,
+            subjects,
+            rate: config.rate,
+            burst: config.burst.max(1.0),
+            policy: config.policy,
+            tokens: config.burst.max(1.0),
+            updated: now,
+            counters: Counters::default(),
+            reported: Counters::default(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    fn wait(&self) -> Duration {
+        Duration::try_from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Send,
+    /// Retry after the duration, `global` blocks every source
+    Wait {
+        after: Duration,
+        global: bool,
+    },
+    /// The command stays spooled to be merged, see [`RateLimiter::release`]
+    Held,
+    Drop,
+}
+
+/// Token buckets for commands going to econ, one global and one per source subject rule.
+pub struct RateLimiter {
+    global: Option<Bucket>,
+    subjects: Vec<Bucket>,
+    report: Duration,
+    last_report: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            global: config
+                .global
+                .as_ref()
+                .map(|limit| Bucket::new("global".to_string(), Vec::new(), limit, now)),
+            subjects: config
+                .subjects
+                .iter()
+                .map(|rule| {
+                    Bucket::new(
+                        rule.subjects.join(","),
+                        rule.subjects.clone(),
+                        &rule.limit,
+                        now,
+                    )
+                })
+                .collect(),
+            report: Duration::from_secs(config.report),
+            last_report: now,
+        }
+    }
+
+    fn bucket(&self, source: &str) -> Option<usize> {
+        self.subjects.iter().position(|bucket| {
+            bucket
+                .subjects
+                .iter()
+                .any(|pattern| subject_matches(pattern, source))
+        })
+    }
+
+    /// Decides what to do with the next command from `source`, `first` is false on retries after a wait.
+    pub fn check(&mut self, source: &str, first: bool, now: Instant) -> Decision {
+        let index = self.bucket(source);
+        if let Some(global) = &mut self.global {
+            global.refill(now);
+        }
+        if let Some(index) = index {
+            self.subjects[index].refill(now);
+        }
+
+        let subject_limited = index.is_some_and(|i| self.subjects[i].tokens < 1.0);
+        let global_limited = self.global.as_ref().is_some_and(|g| g.tokens < 1.0);
+
+        let limiting = match (subject_limited, global_limited) {
+            (false, false) => {
+                for bucket in self.buckets_mut(index) {
+                    bucket.tokens -= 1.0;
+                    bucket.counters.sent += 1;
+                }
+                return Decision::Send;
+            }
+            (true, _) => index.and_then(|i| self.subjects.get_mut(i)),
+            (false, true) => self.global.as_mut(),
+        };
+        let Some(bucket) = limiting else {
+            return Decision::Send;
+        };
+
+        match bucket.policy {
+            LimitPolicy::Delay => {
+                if first {
+                    bucket.counters.delayed += 1;
+                }
+                Decision::Wait {
+                    after: bucket.wait(),
+                    global: !subject_limited,
+                }
+            }
+            LimitPolicy::Merge => {
+                bucket.counters.merged += 1;
+                Decision::Held
+            }
+            LimitPolicy::Drop => {
+                bucket.counters.dropped += 1;
+                Decision::Drop
+            }
+        }
+    }
+
+    fn buckets_mut(&mut self, index: Option<usize>) -> impl Iterator<Item = &mut Bucket> {
+        let subject = match index {
+            Some(i) => self.subjects.get_mut(i),
+            None => None,
+        };
+        self.global.as_mut().into_iter().chain(subject)
+    }
+
+    /// Time until every bucket of `source` has a token.
+    pub fn release_wait(&mut self, source: &str, now: Instant) -> Duration {
+        let index = self.bucket(source);
+        self.buckets_mut(index)
+            .map(|bucket| {
+                bucket.refill(now);
+                bucket.wait()
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Takes a token for the merged line of commands held from `source`.
+    ///
+    /// Returns how long to wait when the buckets of `source` are still empty.
+    pub fn release(&mut self, source: &str, now: Instant) -> Result<(), Duration> {
+        let wait = self.release_wait(source, now);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for bucket in self.buckets_mut(self.bucket(source)) {
+            bucket.tokens -= 1.0;
+            bucket.counters.sent += 1;
+        }
+        Ok(())
+    }
+
+    /// Logs the counters of buckets that changed since the last report.
+    pub fn report(&mut self, name: &str, now: Instant) {
+        if self.report.is_zero() || now.saturating_duration_since(self.last_report) < self.report {
+            return;
+        }
+        self.last_report = now;
+        for bucket in self.global.iter_mut().chain(&mut self.subjects) {
+            if bucket.counters == bucket.reported {
+                continue;
+            }
+            let Counters {
+                sent,
+                delayed,
+                merged,
+                dropped,
+            } = bucket.counters;
+            info!(
+                "[{name}] rate limit {}: sent {sent}, delayed {delayed}, merged {merged}, dropped {dropped}",
+                bucket.name
+            );
+            bucket.reported = bucket.counters;
+        }
+    }
+}
+
+/// Joins the leading `commands` into one console line, returns it with how many it took.
+///
+/// Adjacent `say` texts are combined while they fit into `max_bytes` (0 - no limit), the order
+/// is kept and parts of a split message (`true`) are sent on their own.
+pub fn merge_commands(commands: &[(&str, bool)], max_bytes: usize) -> (String, usize) {
+    let mut statements = Vec::new();
+    let mut say: Option<String> = None;
+    let mut used = 0;
+    for &(command, part) in commands {
+        if part {
+            if used == 0 {
+                return (command.to_string(), 1);
+            }
+            break;
+        }
+        match command.strip_prefix("say ").and_then(say_text) {
+            Some(text) => match &mut say {
+                Some(current) => {
+                    if max_bytes != 0
+                        && current.len() + SAY_SEPARATOR.len() + text.len() > max_bytes
+                    {
+                        break;
+                    }
+                    current.push_str(SAY_SEPARATOR);
+                    current.push_str(&text);
+                }
+                None => say = Some(text),
+            },
+            None => {
+                statements.extend(say.take().map(|text| say_line(&text)));
+                statements.push(command.to_string());
+            }
+        }
+        used += 1;
+    }
+    statements.extend(say.map(|text| say_line(&text)));
+    (statements.join("; "), used)
+}
+
+const SAY_SEPARATOR: &str = " | ";
+
+fn say_line(text: &str) -> String {
+    let mut line = "say ".to_string();
+    quote_into(&mut line, text);
+    line
+}
+
+fn say_text(arg: &str) -> Option<String> {
+    let arg = arg.trim();
+    if arg.starts_with('"') {
+        unquote(arg)
+    } else if arg.contains(['"', ';']) {
+        None
+    } else {
+        Some(arg.to_string())
+    }
+}
+
+fn default_burst() -> f64 {
+    1.0
+}
+
+fn default_report() -> u64 {
+    60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(yaml: &str) -> RateLimiter {
+        RateLimiter::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut limiter = limiter("global: {rate: 1, burst: 2}");
+        let now = Instant::now();
+        assert_eq!(limiter.check("a", true, now), Decision::Send);
+        assert_eq!(limiter.check("a", true, now), Decision::Send);
+        assert!(matches!(
+            limiter.check("a", true, now),
+            Decision::Wait { global: true, .. }
+        ));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check("a", false, later), Decision::Send);
+        assert_eq!(limiter.global.as_ref().unwrap().counters.delayed, 1);
+    }
+
+    #[test]
+    fn test_subject_drop() {
+        let mut limiter =
+            limiter("subjects: [{subjects: [\"tw.econ.write.*\"], rate: 1, policy: drop}]");
+        let now = Instant::now();
+        assert_eq!(limiter.check("tw.econ.write.1", true, now), Decision::Send);
+        assert_eq!(limiter.check("tw.econ.write.1", true, now), Decision::Drop);
+        assert_eq!(
+            limiter.check("tw.econ.moderator", true, now),
+            Decision::Send
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut limiter = limiter("subjects: [{subjects: [task], rate: 1, policy: merge}]");
+        let now = Instant::now();
+        assert_eq!(limiter.check("task", true, now), Decision::Send);
+        assert_eq!(limiter.check("task", true, now), Decision::Held);
+        assert_eq!(limiter.check("task", true, now), Decision::Held);
+        assert!(limiter.release("task", now).is_err());
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.release("task", later).is_ok());
+        assert!(limiter.release("task", later).is_err());
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        for yaml in [
+            "global: {rate: 0}",
+            "subjects: [{subjects: [task], rate: -1}]",
+        ] {
+            let config: RateLimitConfig = serde_yaml::from_str(yaml).unwrap();
+            assert!(config.validate().is_err());
+        }
+        let config: RateLimitConfig = serde_yaml::from_str("global: {rate: 1e-300}").unwrap();
+        assert!(config.validate().is_ok());
+        let limiter = RateLimiter::new(&config);
+        assert_eq!(limiter.global.as_ref().unwrap().wait(), Duration::ZERO);
+        let mut limiter = RateLimiter::new(&config);
+        let now = Instant::now();
+        limiter.check("a", true, now);
+        match limiter.check("a", true, now) {
+            Decision::Wait { after, .. } => assert_eq!(after, MAX_WAIT),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_merge_commands() {
+        let merge = |commands: &[&str], max_bytes| {
+            let commands: Vec<_> = commands.iter().map(|c| (*c, c.contains("/2)"))).collect();
+            merge_commands(&commands, max_bytes)
+        };
+        assert_eq!(
+            merge(&["say \"x\\\"y\"", "say z", "kick 1", "say w"], 0),
+            ("say \"x\\\"y | z\"; kick 1; say \"w\"".to_string(), 4)
+        );
+        // The limit leaves the rest for the next token
+        assert_eq!(
+            merge(&["say aaaa", "say bbbb", "say cccc"], 11),
+            ("say \"aaaa | bbbb\"".to_string(), 2)
+        );
+        // Parts of a split message are never combined
+        assert_eq!(
+            merge(&["say \"(1/2) a\"", "say \"(2/2) b\""], 0),
+            ("say \"(1/2) a\"".to_string(), 1)
+        );
+        assert_eq!(
+            merge(&["say x", "say \"(1/2) a\""], 0),
+            ("say \"x\"".to_string(), 1)
+        );
+    }
+}
@@ -0,0 +1,541 @@
+use crate::econ::events::EconEvent;
+use crate::econ::model::{EconMessage, ROSTER_SOURCE};
+use crate::econ::profile::{LogLine, LogProfile};
+use crate::model::CowStr;
+use crate::nats::Nats;
+use crate::util::hardcoded_regex;
+use async_nats::jetstream::kv::Store;
+use bytes::Bytes;
+use log::{debug, trace, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+/// One line of `status` output per connected client.
+static STATUS_RE: LazyLock<Regex> = LazyLock::new(|| {
+    hardcoded_regex(
+        r"^id=(\d+) addr=\S+ name='(.*?)'(?: clan='(.*?)')?(?: (?:client|score|secure)=|$)",
+    )
+});
+
+/// Echoed after the `status` output of a roster sync, followed by the number of the sync.
+const SYNC_MARKER: &str = "bridge_roster_sync_";
+
+/// Time a roster sync waits for its marker, a sync without the whole output changes nothing.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Player {
+    pub client_id: i32,
+    pub name: String,
+    /// Only known when the server prints it, e.g. in `status`
+    pub clan: Option<String>,
+    /// `None` until the player joined a team or spectators
+    pub team: Option<i32>,
+    /// Unix time in seconds
+    pub joined_at: i64,
+}
+
+impl Player {
+    fn new(client_id: i32, name: &str) -> Self {
+        Self {
+            client_id,
+            name: name.to_string(),
+            clan: None,
+            team: None,
+            joined_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Join,
+    Leave,
+    Update,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RosterChange {
+    pub kind: ChangeKind,
+    pub player: Player,
+}
+
+/// Players of one server as kept in the key-value bucket.
+#[derive(Debug, Clone, Serialize)]
+pub struct RosterSnapshot<'a> {
+    pub server: &'a str,
+    /// Unix time in seconds
+    pub updated_at: i64,
+    pub players: Vec<Player>,
+}
+
+#[derive(Debug, Serialize)]
+struct RosterEvent<'a> {
+    #[serde(flatten)]
+    change: &'a RosterChange,
+    server: &'a str,
+    players: usize,
+    args: &'a Value,
+}
+
+#[derive(Default)]
+struct RosterState {
+    players: BTreeMap<i32, Player>,
+    /// A roster sync waits for its `status` output
+    syncing: bool,
+}
+
+/// Players currently on the server, shared by the reader and the tasks.
+#[derive(Clone)]
+pub struct Roster {
+    state: Arc<RwLock<RosterState>>,
+    changes: broadcast::Sender<RosterChange>,
+}
+
+impl Default for Roster {
+    fn default() -> Self {
+        Self {
+            state: Arc::default(),
+            changes: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl Roster {
+    pub fn count(&self) -> usize {
+        self.read(|state| state.players.len())
+    }
+
+    pub fn players(&self) -> Vec<Player> {
+        self.read(|state| state.players.values().cloned().collect())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RosterChange> {
+        self.changes.subscribe()
+    }
+
+    /// Updates the roster from a parsed econ line and announces the changes.
+    pub fn apply(&self, line: &LogLine, event: Option<&EconEvent>) -> Vec<RosterChange> {
+        let changes = self.write(|state| Self::update(state, line, event));
+        for change in &changes {
+            self.changes.send(change.clone()).ok();
+        }
+        changes
+    }
+
+    fn update(
+        state: &mut RosterState,
+        line: &LogLine,
+        event: Option<&EconEvent>,
+    ) -> Vec<RosterChange> {
+        let change = |kind, player: &Player| {
+            vec![RosterChange {
+                kind,
+                player: player.clone(),
+            }]
+        };
+        match event {
+            Some(EconEvent::Join {
+                client_id,
+                name,
+                team,
+            }) => {
+                let mut kind = ChangeKind::Update;
+                let player = state.players.entry(*client_id).or_insert_with(|| {
+                    kind = ChangeKind::Join;
+                    Player::new(*client_id, name)
+                });
+                if kind == ChangeKind::Update && player.team == Some(*team) && player.name == *name
+                {
+                    return Vec::new();
+                }
+                player.team = Some(*team);
+                player.name.clone_from(name);
+                change(kind, player)
+            }
+            Some(EconEvent::Leave { client_id, .. }) => match state.players.remove(client_id) {
+                Some(player) => change(ChangeKind::Leave, &player),
+                None => Vec::new(),
+            },
+            Some(EconEvent::Rename { from, to }) => {
+                match state.players.values_mut().find(|p| p.name == *from) {
+                    Some(player) => {
+                        player.name.clone_from(to);
+                        change(ChangeKind::Update, player)
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Some(_) => Vec::new(),
+            None => {
+                let Some((client_id, caps)) = status_client(line) else {
+                    return Vec::new();
+                };
+                let (name, clan) = (&caps[2], caps.get(3).map(|m| m.as_str().to_string()));
+                match state.players.get_mut(&client_id) {
+                    Some(player)
+                        if player.name == name && (clan.is_none() || player.clan == clan) =>
+                    {
+                        Vec::new()
+                    }
+                    Some(player) => {
+                        player.name = name.to_string();
+                        player.clan = clan.or(player.clan.take());
+                        change(ChangeKind::Update, player)
+                    }
+                    None => {
+                        let mut player = Player::new(client_id, name);
+                        player.clan = clan;
+                        let result = change(ChangeKind::Join, &player);
+                        state.players.insert(client_id, player);
+                        result
+                    }
+                }
+            }
+        }
+    }
+
+    /// `true` for the `status` output of a running sync and its marker, they are never published.
+    pub fn hides(&self, line: &LogLine) -> bool {
+        line.message.starts_with(SYNC_MARKER)
+            || (self.read(|state| state.syncing) && status_client(line).is_some())
+    }
+
+    /// Drops the players of `listed` missing from a complete `status` output.
+    ///
+    /// Players joining while the output was collected are not in `listed` and kept.
+    fn reconcile(&self, listed: &[i32], seen: &HashSet<i32>) -> Vec<RosterChange> {
+        let changes: Vec<RosterChange> = self.write(|state| {
+            listed
+                .iter()
+                .filter(|id| !seen.contains(id))
+                .filter_map(|id| state.players.remove(id))
+                .map(|player| RosterChange {
+                    kind: ChangeKind::Leave,
+                    player,
+                })
+                .collect()
+        });
+        for change in &changes {
+            self.changes.send(change.clone()).ok();
+        }
+        changes
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&RosterState) -> T) -> T {
+        match self.state.read() {
+            Ok(state) => f(&state),
+            Err(poisoned) => f(&poisoned.into_inner()),
+        }
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut RosterState) -> T) -> T {
+        match self.state.write() {
+            Ok(mut state) => f(&mut state),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+/// Client id of a `status` output line.
+fn status_client<'a>(line: &LogLine<'a>) -> Option<(i32, regex::Captures<'a>)> {
+    if line.system != "server" {
+        return None;
+    }
+    let caps = STATUS_RE.captures(line.message)?;
+    let client_id = caps[1].parse().ok()?;
+    Some((client_id, caps))
+}
+
+/// Asks the server for `status` after every connection and every `interval` seconds,
+/// then drops the players it no longer lists.
+///
+/// The output is read up to an echoed marker, a sync that never sees it changes nothing.
+pub async fn sync_roster(
+    tx: Sender<EconMessage>,
+    roster: Roster,
+    lines: broadcast::Sender<String>,
+    profile: LogProfile,
+    connected: Arc<Notify>,
+    interval: u64,
+) {
+    for sync in 1u64.. {
+        let every = async {
+            match interval {
+                0 => std::future::pending().await,
+                seconds => sleep(Duration::from_secs(seconds)).await,
+            }
+        };
+        tokio::select! {
+            () = connected.notified() => {}
+            () = every => {}
+        }
+
+        let marker = format!("{SYNC_MARKER}{sync}");
+        let listed: Vec<i32> = roster.read(|state| state.players.keys().copied().collect());
+        let mut output = lines.subscribe();
+        roster.write(|state| state.syncing = true);
+        let message = EconMessage {
+            command: format!("status; echo {marker}"),
+            source: ROSTER_SOURCE.to_string(),
+        };
+        if let Err(err) = tx.send(message).await {
+            warn!("tx.send error, roster sync stopped: {err}");
+            return;
+        }
+        let seen = timeout(STATUS_TIMEOUT, read_status(&mut output, profile, &marker)).await;
+        roster.write(|state| state.syncing = false);
+        match seen {
+            Ok(Some(seen)) => {
+                if !roster.reconcile(&listed, &seen).is_empty() {
+                    debug!("Roster reconciled with status, {} players", roster.count());
+                }
+            }
+            _ => debug!("No complete status output, roster sync skipped"),
+        }
+    }
+}
+
+/// Clients listed in the `status` output, `None` if lines were lost before the marker.
+async fn read_status(
+    output: &mut broadcast::Receiver<String>,
+    profile: LogProfile,
+    marker: &str,
+) -> Option<HashSet<i32>> {
+    let mut seen = HashSet::new();
+    loop {
+        let line = output.recv().await.ok()?;
+        let Some(line) = profile.parse(&line) else {
+            continue;
+        };
+        if line.message == marker {
+            return Some(seen);
+        }
+        if let Some((client_id, _)) = status_client(&line) {
+            seen.insert(client_id);
+        }
+    }
+}
+
+/// Where [`publish_roster`] sends the roster.
+pub struct RosterOutput {
+    pub server: String,
+    /// Subject for change events
+    pub subject: Option<CowStr<'static>>,
+    pub store: Option<(Store, String)>,
+    pub args: Value,
+}
+
+/// Publishes every roster change and keeps the key-value snapshot up to date.
+pub async fn publish_roster(nats: Nats, roster: Roster, output: RosterOutput) {
+    let mut changes = roster.subscribe();
+    write_snapshot(&roster, &output).await;
+    loop {
+        let mut pending = Vec::new();
+        match changes.recv().await {
+            Ok(change) => pending.push(change),
+            Err(RecvError::Lagged(count)) => warn!("Roster publisher lagged, {count} changes lost"),
+            Err(RecvError::Closed) => return,
+        }
+        // Changes of one `status` burst end up in a single snapshot
+        while let Ok(change) = changes.try_recv() {
+            pending.push(change);
+        }
+
+        if let Some(subject) = &output.subject {
+            let players = roster.count();
+            for change in &pending {
+                let event = RosterEvent {
+                    change,
+                    server: &output.server,
+                    players,
+                    args: &output.args,
+                };
+                match serde_json::to_string_pretty(&event) {
+                    Ok(json) => {
+                        trace!("Sending roster change to {subject}");
+                        nats.publish_bytes(subject.clone(), Bytes::from(json))
+                            .await
+                            .ok();
+                    }
+                    Err(err) => warn!("Error converting roster change to json: {err}"),
+                }
+            }
+        }
+        write_snapshot(&roster, &output).await;
+    }
+}
+
+async fn write_snapshot(roster: &Roster, output: &RosterOutput) {
+    let Some((store, key)) = &output.store else {
+        return;
+    };
+    let snapshot = RosterSnapshot {
+        server: &output.server,
+        updated_at: chrono::Utc::now().timestamp(),
+        players: roster.players(),
+    };
+    match serde_json::to_vec(&snapshot) {
+        Ok(json) => {
+            if let Err(err) = store.put(key.as_str(), Bytes::from(json)).await {
+                warn!("Failed to write roster \"{key}\": {err}");
+            }
+        }
+        Err(err) => warn!("Error converting roster to json: {err}"),
+    }
+}
+
+/// Task condition on the number of players in the [`Roster`].
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_players: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<usize>,
+    /// Same as `min_players: 1`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub non_empty: bool,
+}
+
+impl PlayerCondition {
+    pub fn matches(&self, players: usize) -> bool {
+        (!self.non_empty || players > 0)
+            && self.min_players.is_none_or(|min| players >= min)
+            && self.max_players.is_none_or(|max| players <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::econ::profile::LogProfile;
+
+    fn apply(roster: &Roster, line: &str) -> Vec<RosterChange> {
+        let line = LogProfile::Ddnet.parse(line).unwrap();
+        let event = EconEvent::parse(&line);
+        roster.apply(&line, event.as_ref())
+    }
+
+    fn kinds(changes: &[RosterChange]) -> Vec<ChangeKind> {
+        changes.iter().map(|change| change.kind).collect()
+    }
+
+    #[test]
+    fn test_join_leave_rename() {
+        let roster = Roster::default();
+        let join = "2024-05-01 12:00:00 I game: team_join player='4:Sheep' team=0";
+        assert_eq!(kinds(&apply(&roster, join)), vec![ChangeKind::Join]);
+        assert!(apply(&roster, join).is_empty());
+        let spectate = "2024-05-01 12:00:01 I game: team_join player='4:Sheep' team=-1";
+        assert_eq!(kinds(&apply(&roster, spectate)), vec![ChangeKind::Update]);
+        apply(
+            &roster,
+            "2024-05-01 12:00:01 I game: team_join player='7:Fox' team=0",
+        );
+        assert_eq!(roster.count(), 2);
+
+        let rename = "2024-05-01 12:00:02 I chat: *** 'Fox' changed name to 'Wolf'";
+        assert_eq!(apply(&roster, rename)[0].player.name, "Wolf");
+        let leave = apply(
+            &roster,
+            "2024-05-01 12:00:03 I game: leave player='4:Sheep'",
+        );
+        assert_eq!(kinds(&leave), vec![ChangeKind::Leave]);
+        assert_eq!(leave[0].player.team, Some(-1));
+        assert_eq!(roster.players()[0].name, "Wolf");
+    }
+
+    #[tokio::test]
+    async fn test_status_sync() {
+        let roster = Roster::default();
+        let mut changes = roster.subscribe();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let (lines, _) = broadcast::channel(16);
+        let connected = Arc::new(Notify::new());
+        // The server output as the reader sees it: roster first, then the line subscribers
+        let read = |line: &str| {
+            let hidden = LogProfile::Ddnet
+                .parse(line)
+                .is_some_and(|parsed| roster.hides(&parsed));
+            apply(&roster, line);
+            lines.send(line.to_string()).ok();
+            hidden
+        };
+        read("2024-05-01 12:00:00 I game: team_join player='4:Sheep' team=0");
+        read("2024-05-01 12:00:00 I game: team_join player='5:Fox' team=0");
+
+        connected.notify_one();
+        let sync = tokio::spawn(sync_roster(
+            tx,
+            roster.clone(),
+            lines.clone(),
+            LogProfile::Ddnet,
+            connected.clone(),
+            0,
+        ));
+        let command = rx.recv().await.unwrap().command;
+        assert_eq!(command, "status; echo bridge_roster_sync_1");
+
+        let status = "2024-05-01 12:00:01 I server: id=2 addr=<{127.0.0.1:5000}> name='it's me' clan='Team' client=16050 secure=yes flags=0";
+        assert!(read(status));
+        assert!(read(
+            "2024-05-01 12:00:01 I server: id=5 addr=<{127.0.0.1:5001}> name='Fox' client=16050 secure=yes flags=0"
+        ));
+        assert!(!read("2024-05-01 12:00:01 I chat: 2:-2:it's me: hi"));
+        assert!(read("2024-05-01 12:00:01 I console: bridge_roster_sync_1"));
+        while roster.count() != 2 {
+            tokio::task::yield_now().await;
+        }
+        let names: Vec<String> = roster.players().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["it's me", "Fox"]);
+        assert_eq!(roster.players()[0].clan.as_deref(), Some("Team"));
+        // Status lines outside of a sync are published
+        while roster.read(|state| state.syncing) {
+            tokio::task::yield_now().await;
+        }
+        assert!(!read(status));
+
+        // A connection without the marker leaves the roster alone
+        connected.notify_one();
+        assert_eq!(
+            rx.recv().await.unwrap().command,
+            "status; echo bridge_roster_sync_2"
+        );
+        read(status);
+        sync.abort();
+        assert_eq!(roster.count(), 2);
+
+        let received: Vec<ChangeKind> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                ChangeKind::Join,
+                ChangeKind::Join,
+                ChangeKind::Join,
+                ChangeKind::Leave
+            ]
+        );
+    }
+
+    #[test]
+    fn test_condition() {
+        let condition: PlayerCondition =
+            serde_yaml::from_str("{non_empty: true, max_players: 3}").unwrap();
+        assert!(!condition.matches(0));
+        assert!(condition.matches(3));
+        assert!(!condition.matches(4));
+        assert!(PlayerCondition::default().matches(0));
+    }
+}
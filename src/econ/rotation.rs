@@ -0,0 +1,341 @@
+use crate::econ::command::{CommandArg, CommandTemplate, EconCommand};
+use crate::econ::enums::TaskType;
+use log::{info, warn};
+use rand::distributions::WeightedIndex;
+use rand::prelude::{Distribution, SliceRandom};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+pub type RotationState = Arc<Mutex<Rotation>>;
+
+/// `command` is a raw line, or the command name when `args` are given.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WeightedCommand {
+    pub command: CommandTemplate,
+    #[serde(default)]
+    pub args: Vec<CommandArg>,
+    pub weight: u32,
+}
+
+impl WeightedCommand {
+    fn into_template(self) -> CommandTemplate {
+        match self.command {
+            CommandTemplate::Line(command) if !self.args.is_empty() => {
+                CommandTemplate::Command(EconCommand {
+                    command,
+                    args: self.args,
+                })
+            }
+            command => command,
+        }
+    }
+}
+
+/// Entry of a YAML messages file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum FileEntry {
+    Weighted(WeightedCommand),
+    Plain(CommandTemplate),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    line: String,
+    weight: u32,
+}
+
+/// Rotation position, kept in `position_file` across restarts.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Position {
+    index: usize,
+    /// Remaining entries of the current shuffle round
+    #[serde(default)]
+    order: Vec<usize>,
+    #[serde(default)]
+    last: Option<usize>,
+}
+
+/// Commands of a scheduled task and the choice of the next ones to send.
+#[derive(Default, Debug)]
+pub struct Rotation {
+    kind: TaskType,
+    entries: Vec<Entry>,
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    position_file: Option<PathBuf>,
+    position: Position,
+    loaded: bool,
+}
+
+impl Rotation {
+    pub fn new(
+        kind: TaskType,
+        lines: Vec<String>,
+        file: Option<PathBuf>,
+        position_file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            kind,
+            entries: lines
+                .into_iter()
+                .map(|line| Entry { line, weight: 1 })
+                .collect(),
+            file,
+            position_file,
+            ..Default::default()
+        }
+    }
+
+    /// Commands to send for the next run.
+    pub async fn next(&mut self) -> Vec<String> {
+        if !self.loaded {
+            self.loaded = true;
+            self.load_position().await;
+        }
+        self.reload().await;
+        if self.entries.is_empty() {
+            warn!("rotation: No commands available");
+            return Vec::new();
+        }
+
+        let chosen = match self.kind {
+            TaskType::All => return self.entries.iter().map(|e| e.line.clone()).collect(),
+            TaskType::Line => {
+                let index = self.position.index % self.entries.len();
+                self.position.index = index + 1;
+                index
+            }
+            TaskType::Random => rand::thread_rng().gen_range(0..self.entries.len()),
+            TaskType::Weighted => match WeightedIndex::new(self.entries.iter().map(|e| e.weight)) {
+                Ok(dist) => dist.sample(&mut rand::thread_rng()),
+                Err(err) => {
+                    warn!("rotation: Invalid weights: {err}");
+                    return Vec::new();
+                }
+            },
+            TaskType::Shuffle => self.next_shuffled(),
+        };
+        self.position.last = Some(chosen);
+        self.save_position().await;
+        vec![self.entries[chosen].line.clone()]
+    }
+
+    fn next_shuffled(&mut self) -> usize {
+        let len = self.entries.len();
+        if self.position.order.is_empty() || self.position.order.iter().any(|&i| i >= len) {
+            let mut order: Vec<usize> = (0..len).collect();
+            order.shuffle(&mut rand::thread_rng());
+            // The first entry of a round never repeats the last one of the previous round
+            if len > 1 && self.position.last == order.last().copied() {
+                order.swap(0, len - 1);
+            }
+            self.position.order = order;
+        }
+        self.position.order.pop().unwrap_or_default()
+    }
+
+    /// Reads the messages file again if it changed since the last read.
+    async fn reload(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let modified = match fs::metadata(file).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(err) => {
+                if self.modified.is_none() {
+                    warn!("Failed to read messages file '{}': {err}", file.display());
+                    self.modified = Some(SystemTime::UNIX_EPOCH);
+                }
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+        self.modified = Some(modified);
+
+        match read_entries(file).await {
+            Ok(entries) => {
+                info!(
+                    "Loaded {} messages from '{}'",
+                    entries.len(),
+                    file.display()
+                );
+                if entries != self.entries {
+                    self.position.order.clear();
+                }
+                self.entries = entries;
+            }
+            Err(err) => warn!("Keeping previous messages, '{}': {err}", file.display()),
+        }
+    }
+
+    async fn load_position(&mut self) {
+        let Some(path) = &self.position_file else {
+            return;
+        };
+        match fs::read(path).await {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(position) => self.position = position,
+                Err(err) => warn!("Ignoring rotation position '{}': {err}", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!(
+                "Failed to read rotation position '{}': {err}",
+                path.display()
+            ),
+        }
+    }
+
+    async fn save_position(&self) {
+        let Some(path) = &self.position_file else {
+            return;
+        };
+        let json = match serde_json::to_vec(&self.position) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Error converting rotation position to json: {err}");
+                return;
+            }
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.ok();
+        }
+        let tmp = path.with_extension("tmp");
+        let result = match fs::write(&tmp, json).await {
+            Ok(()) => fs::rename(&tmp, path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!(
+                "Failed to write rotation position '{}': {err}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Reads a messages file: YAML list for `.yaml`/`.yml`, otherwise one command per line.
+async fn read_entries(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let contents = fs::read_to_string(path).await?;
+    let yaml = path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml");
+    if !yaml {
+        return Ok(parse_text(&contents));
+    }
+
+    let mut entries = Vec::new();
+    for entry in serde_yaml::from_str::<Vec<FileEntry>>(&contents)? {
+        let (command, weight) = match entry {
+            FileEntry::Weighted(entry) => {
+                let weight = entry.weight;
+                (entry.into_template(), weight)
+            }
+            FileEntry::Plain(command) => (command, 1),
+        };
+        match command.to_line() {
+            Ok(line) => entries.push(Entry { line, weight }),
+            Err(err) => warn!("Skipping message: {err}"),
+        }
+    }
+    Ok(entries)
+}
+
+/// One command per line, empty lines and `#` comments are skipped.
+fn parse_text(contents: &str) -> Vec<Entry> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Entry {
+            line: line.to_string(),
+            weight: 1,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn rotation(kind: TaskType, lines: &[&str]) -> Rotation {
+        let lines = lines.iter().map(ToString::to_string).collect();
+        Rotation::new(kind, lines, None, None)
+    }
+
+    #[tokio::test]
+    async fn test_line() {
+        let mut rotation = rotation(TaskType::Line, &["a", "b"]);
+        assert_eq!(rotation.next().await, vec!["a"]);
+        assert_eq!(rotation.next().await, vec!["b"]);
+        assert_eq!(rotation.next().await, vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_shuffle_without_repeat() {
+        let mut rotation = rotation(TaskType::Shuffle, &["a", "b", "c"]);
+        let mut previous = String::new();
+        for _ in 0..10 {
+            let round: Vec<String> = [
+                rotation.next().await,
+                rotation.next().await,
+                rotation.next().await,
+            ]
+            .concat();
+            assert_eq!(round.iter().collect::<HashSet<_>>().len(), 3);
+            assert_ne!(round[0], previous);
+            previous = round[2].clone();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_and_position() {
+        let dir = std::env::temp_dir().join(format!("bridge-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let file = dir.join("messages.yaml");
+        let position = dir.join("position.json");
+        fs::write(
+            &file,
+            "- say one\n- command: say two\n  weight: 0\n- {command: say, args: [three]}\n\
+             - {command: say, args: [\"four; five\"], weight: 3}\n",
+        )
+        .await
+        .unwrap();
+
+        let mut rotation = Rotation::new(
+            TaskType::Line,
+            Vec::new(),
+            Some(file.clone()),
+            Some(position.clone()),
+        );
+        assert_eq!(rotation.next().await, vec!["say one"]);
+        assert_eq!(rotation.next().await, vec!["say two"]);
+
+        let mut restarted = Rotation::new(TaskType::Line, Vec::new(), Some(file), Some(position));
+        assert_eq!(restarted.next().await, vec![r#"say "three""#]);
+
+        assert_eq!(restarted.next().await, vec![r#"say "four; five""#]);
+        assert_eq!(restarted.entries[3].weight, 3);
+
+        restarted.kind = TaskType::Weighted;
+        for _ in 0..10 {
+            assert_ne!(restarted.next().await, vec!["say two"]);
+        }
+        fs::remove_dir_all(dir).await.ok();
+    }
+
+    #[test]
+    fn test_parse_text() {
+        let entries = parse_text("# header\nsay a\n\n  say b  \n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].line, "say b");
+    }
+}
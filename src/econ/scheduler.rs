@@ -0,0 +1,396 @@
+use crate::econ::enums::{NamedTask, Task, TaskContext, TaskStatus};
+use crate::econ::policy::CommandPolicy;
+use async_nats::jetstream::kv::Store;
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::task::{AbortHandle, JoinSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskAction {
+    List,
+    Pause,
+    Resume,
+    Trigger,
+    Add,
+    Remove,
+}
+
+/// Request on the control subject.
+#[derive(Debug, Deserialize)]
+pub struct TaskControl {
+    pub action: TaskAction,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Task for `add`
+    #[serde(default)]
+    pub task: Option<NamedTask>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskInfo {
+    pub name: String,
+    pub kind: &'static str,
+    pub paused: bool,
+    /// RFC 3339, local time
+    pub next_fire: Option<String>,
+    pub task: Task,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskControlReply {
+    pub ok: bool,
+    pub error: Option<String>,
+    pub tasks: Vec<TaskInfo>,
+}
+
+/// Where tasks changed at runtime are kept.
+pub struct TaskStore {
+    pub store: Store,
+    pub key: String,
+}
+
+struct ManagedTask {
+    name: String,
+    task: Arc<Task>,
+    status: Arc<TaskStatus>,
+    handle: Option<AbortHandle>,
+}
+
+/// Tasks of one server, dropping the manager stops all of them.
+pub struct TaskManager {
+    ctx: TaskContext,
+    tasks: Vec<ManagedTask>,
+    running: JoinSet<()>,
+    store: Option<TaskStore>,
+    policy: Arc<CommandPolicy>,
+    /// Control subject the policy is checked for
+    subject: String,
+}
+
+impl TaskManager {
+    pub fn new(
+        ctx: TaskContext,
+        store: Option<TaskStore>,
+        policy: Arc<CommandPolicy>,
+        subject: String,
+    ) -> Self {
+        Self {
+            ctx,
+            tasks: Vec::new(),
+            running: JoinSet::new(),
+            store,
+            policy,
+            subject,
+        }
+    }
+
+    /// Starts the tasks saved in the store, or `configured` if nothing was saved yet.
+    ///
+    /// Saved tasks may come from the control subject and are checked against its policy.
+    pub async fn start(&mut self, configured: Vec<NamedTask>) {
+        let (tasks, checked) = match self.load().await {
+            Some(saved) => {
+                info!("Loaded {} tasks from the key-value store", saved.len());
+                (saved, true)
+            }
+            None => (configured, false),
+        };
+        for task in tasks {
+            let result = match checked {
+                true => self.check(&task.task).and_then(|_| self.add(task)),
+                false => self.add(task),
+            };
+            if let Err(err) = result {
+                warn!("Skipping task: {err}");
+            }
+        }
+    }
+
+    /// Checks the commands of `task` against the policy of the control subject.
+    fn check(&self, task: &Task) -> Result<(), String> {
+        for template in task.templates() {
+            let command = template
+                .render(&self.ctx.args, &[] as &[&str])
+                .map_err(|e| e.to_string())?;
+            self.policy
+                .check(&self.subject, &command)
+                .map_err(|reason| format!("task rejected: {reason}"))?;
+        }
+        Ok(())
+    }
+
+    async fn load(&self) -> Option<Vec<NamedTask>> {
+        let TaskStore { store, key } = self.store.as_ref()?;
+        match store.get(key.as_str()).await {
+            Ok(Some(value)) => serde_json::from_slice(&value)
+                .inspect_err(|e| warn!("Ignoring saved tasks \"{key}\": {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Failed to load saved tasks \"{key}\": {err}");
+                None
+            }
+        }
+    }
+
+    async fn save(&self) {
+        let Some(TaskStore { store, key }) = &self.store else {
+            return;
+        };
+        let tasks: Vec<NamedTask> = self
+            .tasks
+            .iter()
+            .map(|managed| NamedTask {
+                name: Some(managed.name.clone()),
+                paused: managed.handle.is_none(),
+                task: Task::clone(&managed.task),
+            })
+            .collect();
+        match serde_json::to_vec(&tasks) {
+            Ok(json) => {
+                if let Err(err) = store.put(key.as_str(), Bytes::from(json)).await {
+                    warn!("Failed to save tasks \"{key}\": {err}");
+                }
+            }
+            Err(err) => warn!("Error converting tasks to json: {err}"),
+        }
+    }
+
+    /// Applies a control request and replies with the resulting task list.
+    pub async fn handle(&mut self, request: TaskControl) -> TaskControlReply {
+        while self.running.try_join_next().is_some() {}
+
+        let name = request.name.as_deref().unwrap_or_default();
+        let result = match request.action {
+            TaskAction::List => Ok(()),
+            TaskAction::Pause => self.pause(name),
+            TaskAction::Resume => self.resume(name),
+            TaskAction::Trigger => self.trigger(name),
+            TaskAction::Add => match request.task {
+                // commands of a messages file can change at any time and are never checked
+                Some(task) if task.task.messages_file().is_some() => {
+                    Err("messages files can only be set in the config".to_string())
+                }
+                Some(mut task) => {
+                    task.name = task.name.or(request.name);
+                    self.check(&task.task)
+                        .and_then(|_| self.add(task).map(|_| ()))
+                }
+                None => Err("`add` needs a task".to_string()),
+            },
+            TaskAction::Remove => self.remove(name),
+        };
+        if result.is_ok() && !matches!(request.action, TaskAction::List | TaskAction::Trigger) {
+            self.save().await;
+        }
+
+        TaskControlReply {
+            ok: result.is_ok(),
+            error: result.err(),
+            tasks: self.list(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<TaskInfo> {
+        self.tasks
+            .iter()
+            .map(|managed| TaskInfo {
+                name: managed.name.clone(),
+                kind: managed.task.kind(),
+                paused: managed.handle.is_none(),
+                next_fire: managed
+                    .handle
+                    .as_ref()
+                    .and_then(|_| managed.status.next_fire())
+                    .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+                    .map(|time| time.with_timezone(&chrono::Local).to_rfc3339()),
+                task: Task::clone(&managed.task),
+            })
+            .collect()
+    }
+
+    pub fn add(&mut self, task: NamedTask) -> Result<String, String> {
+        let name = match task.name {
+            Some(name) if self.find(&name).is_ok() => {
+                return Err(format!("task \"{name}\" already exists"));
+            }
+            Some(name) => name,
+            None => (1..)
+                .map(|index| format!("task-{index}"))
+                .find(|name| self.find(name).is_err())
+                .unwrap_or_default(),
+        };
+        task.task.validate().map_err(|e| e.to_string())?;
+        let mut inner = task.task;
+        inner.init_state();
+        self.tasks.push(ManagedTask {
+            name: name.clone(),
+            task: Arc::new(inner),
+            status: Arc::default(),
+            handle: None,
+        });
+        if !task.paused {
+            let index = self.tasks.len() - 1;
+            self.spawn(index);
+        }
+        Ok(name)
+    }
+
+    fn find(&self, name: &str) -> Result<usize, String> {
+        self.tasks
+            .iter()
+            .position(|managed| managed.name == name)
+            .ok_or_else(|| format!("task \"{name}\" not found"))
+    }
+
+    fn spawn(&mut self, index: usize) {
+        let managed = &mut self.tasks[index];
+        let (task, status, ctx) = (
+            managed.task.clone(),
+            managed.status.clone(),
+            self.ctx.clone(),
+        );
+        managed.handle = Some(self.running.spawn(async move {
+            task.execute(&ctx, &status).await;
+        }));
+    }
+
+    fn pause(&mut self, name: &str) -> Result<(), String> {
+        let index = self.find(name)?;
+        match self.tasks[index].handle.take() {
+            Some(handle) => {
+                handle.abort();
+                Ok(())
+            }
+            None => Err(format!("task \"{name}\" is already paused")),
+        }
+    }
+
+    fn resume(&mut self, name: &str) -> Result<(), String> {
+        let index = self.find(name)?;
+        if self.tasks[index].handle.is_some() {
+            return Err(format!("task \"{name}\" is already running"));
+        }
+        self.spawn(index);
+        Ok(())
+    }
+
+    fn trigger(&mut self, name: &str) -> Result<(), String> {
+        let index = self.find(name)?;
+        self.check(&self.tasks[index].task)?;
+        let (task, ctx) = (self.tasks[index].task.clone(), self.ctx.clone());
+        self.running.spawn(async move {
+            task.run_once(&ctx).await;
+        });
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), String> {
+        let index = self.find(name)?;
+        if let Some(handle) = self.tasks.remove(index).handle {
+            handle.abort();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::econ::policy::PolicyConfig;
+    use crate::econ::profile::LogProfile;
+    use crate::econ::roster::Roster;
+    use serde_yaml::Value;
+    use tokio::sync::{broadcast, mpsc};
+
+    fn request(yaml: &str) -> TaskControl {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_control() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let (lines, _) = broadcast::channel(8);
+        let ctx = TaskContext {
+            tx,
+            args: Value::Null,
+            profile: LogProfile::Ddnet,
+            lines,
+            roster: Roster::default(),
+        };
+        let policy: PolicyConfig = serde_yaml::from_str(
+            "rules: [{subjects: [tw.econ.tasks.*], deny: [{command: shutdown}, {command: exec}]}]",
+        )
+        .unwrap();
+        let policy = Arc::new(CommandPolicy::new(&policy, None).unwrap());
+        let mut manager = TaskManager::new(ctx, None, policy, "tw.econ.tasks.1".to_string());
+        manager
+            .start(vec![
+                serde_yaml::from_str(
+                    "{name: save, paused: true, delay: 60, commands: [bans_save bans]}",
+                )
+                .unwrap(),
+                serde_yaml::from_str("{name: reload, paused: true, delay: 60, commands: [exec x]}")
+                    .unwrap(),
+            ])
+            .await;
+
+        let reply = manager
+            .handle(request(
+                "{action: add, task: {cron: '0 0 * * * *', commands: [say hi]}}",
+            ))
+            .await;
+        assert!(reply.ok);
+        assert_eq!(reply.tasks[2].name, "task-1");
+        assert_eq!(reply.tasks[2].kind, "cron");
+        assert!(reply.tasks[0].paused);
+        assert!(reply.tasks[0].next_fire.is_none());
+
+        let reply = manager
+            .handle(request("{action: trigger, name: save}"))
+            .await;
+        assert!(reply.ok);
+        assert_eq!(rx.recv().await.unwrap().command, "bans_save bans");
+
+        for request_yaml in [
+            "{action: add, task: {delay: 60, commands: [say hi, \"say x; shutdown\"]}}",
+            "{action: add, task: {countdown: {at: '2099-01-01 00:00'}, warning: [exec a], commands: []}}",
+            "{action: add, task: {delay: 60, file: messages.txt, commands: []}}",
+            "{action: trigger, name: reload}",
+            "{action: add, task: {delay: 18446744073709551615, commands: [say hi]}}",
+            "{action: add, task: {cron: '* * * * * *', jitter: 9223372036854775807, commands: []}}",
+            "{action: add, task: {countdown: {at: '2099-01-01 00:00', offsets: [999999999d]}, commands: []}}",
+        ] {
+            let reply = manager.handle(request(request_yaml)).await;
+            assert!(!reply.ok, "{request_yaml}");
+            assert_eq!(reply.tasks.len(), 3);
+        }
+        assert!(rx.try_recv().is_err());
+
+        assert!(
+            !manager
+                .handle(request("{action: pause, name: save}"))
+                .await
+                .ok
+        );
+        assert!(
+            manager
+                .handle(request("{action: pause, name: task-1}"))
+                .await
+                .ok
+        );
+        let reply = manager
+            .handle(request("{action: remove, name: save}"))
+            .await;
+        assert_eq!(reply.tasks.len(), 2);
+        assert!(
+            !manager
+                .handle(request("{action: resume, name: save}"))
+                .await
+                .ok
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use crate::econ::priority::Priority;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpooledCommand {
+    pub command: String,
+    /// Subject the command came from
+    #[serde(default)]
+    pub source: String,
+    /// Unix time in seconds
+    pub queued_at: i64,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Already held back by the rate limiter
+    #[serde(skip)]
+    pub delayed: bool,
+    /// Waiting to be merged with other commands of its source, see [`RateLimiter::release`]
+    ///
+    /// [`RateLimiter::release`]: crate::econ::rate_limit::RateLimiter::release
+    #[serde(default)]
+    pub held: bool,
+    /// One of the numbered parts of a split chat message, never merged
+    #[serde(default)]
+    pub part: bool,
+}
+
+/// Commands waiting to be sent to econ, optionally mirrored to a JSON lines file.
+pub struct Spool {
+    path: Option<PathBuf>,
+    max_commands: usize,
+    expire: i64,
+    queue: VecDeque<SpooledCommand>,
+    on_disk: usize,
+    dirty: bool,
+}
+
+impl Spool {
+    pub fn new(path: Option<PathBuf>, max_commands: usize, expire: u64) -> Self {
+        Self {
+            path,
+            max_commands,
+            expire: i64::try_from(expire).unwrap_or(i64::MAX),
+            queue: VecDeque::new(),
+            on_disk: 0,
+            dirty: false,
+        }
+    }
+
+    /// Reads commands left over from a previous run.
+    pub async fn load(mut self) -> Self {
+        let Some(path) = &self.path else {
+            return self;
+        };
+        let contents = match fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return self,
+            Err(err) => {
+                warn!("Failed to read spool '{}': {err}", path.display());
+                return self;
+            }
+        };
+
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            match serde_json::from_str::<SpooledCommand>(line) {
+                Ok(command) => self.queue.push_back(command),
+                Err(err) => warn!("Skipping broken spool entry '{line}': {err}"),
+            }
+        }
+        self.on_disk = self.queue.len();
+        self.truncate();
+        self
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&SpooledCommand> {
+        self.queue.get(index)
+    }
+
+    /// Queues a command behind the ones of its priority and above.
+    pub fn push(&mut self, command: String, source: String, priority: Priority, part: bool) {
+        let index = self
+            .queue
+            .iter()
+            .rposition(|c| c.priority >= priority)
+            .map_or(0, |index| index + 1);
+        self.queue.insert(
+            index,
+            SpooledCommand {
+                command,
+                source,
+                queued_at: chrono::Utc::now().timestamp(),
+                priority,
+                delayed: false,
+                held: false,
+                part,
+            },
+        );
+        self.dirty = true;
+        self.truncate();
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if self.queue.remove(index).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    pub fn mark_held(&mut self, index: usize) {
+        if let Some(command) = self.queue.get_mut(index) {
+            command.held = true;
+            self.dirty = true;
+        }
+    }
+
+    /// Positions of the held commands of `source`, oldest first.
+    pub fn held(&self, source: &str) -> Vec<usize> {
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.held && c.source == source)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn mark_delayed(&mut self, index: usize) {
+        if let Some(command) = self.queue.get_mut(index) {
+            command.delayed = true;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty |= !self.queue.is_empty();
+        self.queue.clear();
+    }
+
+    /// Removes commands older than `expire` seconds, returns how many were dropped.
+    pub fn drop_expired(&mut self, now: i64) -> usize {
+        if self.expire == 0 {
+            return 0;
+        }
+        let before = self.queue.len();
+        self.queue
+            .retain(|c| now.saturating_sub(c.queued_at) < self.expire);
+        let dropped = before - self.queue.len();
+        self.dirty |= dropped != 0;
+        dropped
+    }
+
+    /// Drops the oldest commands of the lowest priority first.
+    fn truncate(&mut self) {
+        if self.max_commands == 0 || self.queue.len() <= self.max_commands {
+            return;
+        }
+        let overflow = self.queue.len() - self.max_commands;
+        warn!("Spool is full, dropping {overflow} commands");
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            while self.queue.len() > self.max_commands {
+                let Some(index) = self.queue.iter().position(|c| c.priority == priority) else {
+                    break;
+                };
+                self.queue.remove(index);
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Writes the queue to disk if it changed since the last sync.
+    pub async fn sync(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !self.dirty || (self.queue.is_empty() && self.on_disk == 0) {
+            self.dirty = false;
+            return;
+        }
+
+        let mut contents = String::new();
+        for command in &self.queue {
+            match serde_json::to_string(command) {
+                Ok(line) => {
+                    contents.push_str(&line);
+                    contents.push('\n');
+                }
+                Err(err) => warn!("Failed to serialize spool entry: {err}"),
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.ok();
+        }
+        let tmp = path.with_extension("tmp");
+        let result = match fs::write(&tmp, contents).await {
+            Ok(()) => fs::rename(&tmp, path).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                self.on_disk = self.queue.len();
+                self.dirty = false;
+            }
+            Err(err) => error!("Failed to write spool '{}': {err}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_drops_oldest() {
+        let mut spool = Spool::new(None, 2, 0);
+        spool.push("a".into(), String::new(), Priority::Normal, false);
+        spool.push("b".into(), String::new(), Priority::Normal, false);
+        spool.push("c".into(), String::new(), Priority::Normal, false);
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.get(0).unwrap().command, "b");
+    }
+
+    #[test]
+    fn test_priority_order_and_overflow() {
+        let mut spool = Spool::new(None, 4, 0);
+        spool.push("say 1".into(), String::new(), Priority::Normal, false);
+        spool.push("say task".into(), "task".into(), Priority::Low, false);
+        spool.push("say 2".into(), String::new(), Priority::Normal, false);
+        spool.push("kick 1".into(), String::new(), Priority::High, false);
+        let order: Vec<_> = spool.queue.iter().map(|c| c.command.as_str()).collect();
+        assert_eq!(order, ["kick 1", "say 1", "say 2", "say task"]);
+
+        spool.push("kick 2".into(), String::new(), Priority::High, false);
+        spool.push("say 3".into(), String::new(), Priority::Normal, false);
+        let order: Vec<_> = spool.queue.iter().map(|c| c.command.as_str()).collect();
+        assert_eq!(order, ["kick 1", "kick 2", "say 2", "say 3"]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut spool = Spool::new(None, 0, 60);
+        spool.push("old".into(), String::new(), Priority::Normal, false);
+        spool.push("new".into(), String::new(), Priority::Normal, false);
+        spool.queue[0].queued_at -= 120;
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(spool.drop_expired(now), 1);
+        assert_eq!(spool.get(0).unwrap().command, "new");
+    }
+
+    #[tokio::test]
+    async fn test_persist_and_load() {
+        let path = std::env::temp_dir().join(format!("bridge-spool-{}.jsonl", std::process::id()));
+        let mut spool = Spool::new(Some(path.clone()), 10, 0);
+        spool.push(
+            "say one".into(),
+            "tw.econ.write.1".into(),
+            Priority::Normal,
+            false,
+        );
+        spool.push("say \"two\"".into(), "task".into(), Priority::Normal, false);
+        spool.mark_held(1);
+        spool.sync().await;
+
+        let loaded = Spool::new(Some(path.clone()), 10, 0).load().await;
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(0).unwrap().command, "say one");
+        assert_eq!(loaded.get(0).unwrap().source, "tw.econ.write.1");
+        assert_eq!(loaded.held("task"), [1]);
+
+        spool.remove(0);
+        spool.remove(0);
+        spool.sync().await;
+        let loaded = Spool::new(Some(path.clone()), 10, 0).load().await;
+        assert!(loaded.is_empty());
+
+        fs::remove_file(path).await.ok();
+    }
+}
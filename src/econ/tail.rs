@@ -0,0 +1,297 @@
+use crate::format_values;
+use crate::model::CowStr;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+
+/// Server log file read instead of the econ output.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogSourceConfig {
+    pub path: CowStr<'static>,
+    /// File keeping the read position across restarts
+    #[serde(default)]
+    pub offset_file: Option<CowStr<'static>>,
+    /// Milliseconds between checks for new lines
+    #[serde(default = "default_poll")]
+    pub poll: u64,
+    /// Without a saved position read the file from the beginning instead of the end
+    #[serde(default)]
+    pub from_start: bool,
+    /// Send commands over econ, `false` - no econ connection at all
+    #[serde(default = "default_commands")]
+    pub commands: bool,
+}
+
+impl LogSourceConfig {
+    pub fn source(&self, args: &Value) -> LogSource {
+        let path: CowStr = format_values!(self.path, args, &[] as &[&str]; single);
+        let offset_file = self.offset_file.as_ref().map(|path| {
+            let path: CowStr = format_values!(path, args, &[] as &[&str]; single);
+            PathBuf::from(path.as_ref())
+        });
+        LogSource {
+            tail: Arc::new(Mutex::new(LogTail::new(
+                PathBuf::from(path.as_ref()),
+                offset_file,
+                self.from_start,
+            ))),
+            poll: Duration::from_millis(self.poll),
+        }
+    }
+}
+
+/// Log file shared by the readers of one pipeline, a restarted reader continues where the last one stopped.
+#[derive(Clone)]
+pub struct LogSource {
+    pub tail: Arc<Mutex<LogTail>>,
+    pub poll: Duration,
+}
+
+/// Read position, `id` tells a rotated file from the one the offset belongs to.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Offset {
+    id: u64,
+    offset: u64,
+}
+
+/// Follows a log file like `tail -F`.
+pub struct LogTail {
+    path: PathBuf,
+    offset_file: Option<PathBuf>,
+    from_start: bool,
+    file: Option<File>,
+    /// Bytes of the current file read so far
+    read_to: u64,
+    id: u64,
+    /// Unfinished last line
+    partial: Vec<u8>,
+    started: bool,
+}
+
+impl LogTail {
+    pub fn new(path: PathBuf, offset_file: Option<PathBuf>, from_start: bool) -> Self {
+        Self {
+            path,
+            offset_file,
+            from_start,
+            file: None,
+            read_to: 0,
+            id: 0,
+            partial: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Complete lines written since the last call.
+    pub async fn read_lines(&mut self) -> anyhow::Result<Vec<String>> {
+        if self.file.is_none() && !self.open().await? {
+            return Ok(Vec::new());
+        }
+        let mut lines = self.read_available().await?;
+
+        match fs::metadata(&self.path).await {
+            Ok(meta) if file_id(&meta) != self.id => {
+                // The old file was read to its end above
+                info!("Log file '{}' rotated", self.path.display());
+                if !self.partial.is_empty() {
+                    lines.push(take_line(&mut self.partial));
+                }
+                self.file = None;
+                self.read_to = 0;
+                if self.open().await? {
+                    lines.extend(self.read_available().await?);
+                }
+            }
+            Ok(meta) if meta.len() < self.read_to => {
+                info!("Log file '{}' truncated", self.path.display());
+                self.partial.clear();
+                self.read_to = 0;
+                if let Some(file) = &mut self.file {
+                    file.seek(SeekFrom::Start(0)).await?;
+                }
+                lines.extend(self.read_available().await?);
+            }
+            Ok(_) => {}
+            // Between the rename and the creation of the new file
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if !lines.is_empty() {
+            self.save_offset().await;
+        }
+        Ok(lines)
+    }
+
+    /// Opens the file at the saved position, `false` while it doesn't exist.
+    async fn open(&mut self) -> anyhow::Result<bool> {
+        let mut file = match File::open(&self.path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("Waiting for log file '{}'", self.path.display());
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let meta = file.metadata().await?;
+        self.id = file_id(&meta);
+
+        if !self.started {
+            self.started = true;
+            self.read_to = match self.load_offset().await {
+                Some(saved) if saved.id == self.id && saved.offset <= meta.len() => saved.offset,
+                // Rotated while we were not running
+                Some(_) => 0,
+                None if self.from_start => 0,
+                None => meta.len(),
+            };
+        }
+        file.seek(SeekFrom::Start(self.read_to)).await?;
+        self.file = Some(file);
+        Ok(true)
+    }
+
+    async fn read_available(&mut self) -> anyhow::Result<Vec<String>> {
+        let Some(file) = &mut self.file else {
+            return Ok(Vec::new());
+        };
+        let mut buf = Vec::new();
+        self.read_to += file.read_to_end(&mut buf).await? as u64;
+        self.partial.extend_from_slice(&buf);
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            self.partial.pop();
+            lines.push(take_line(&mut self.partial));
+            self.partial = rest;
+        }
+        Ok(lines)
+    }
+
+    async fn load_offset(&self) -> Option<Offset> {
+        let path = self.offset_file.as_ref()?;
+        match fs::read(path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .inspect_err(|e| warn!("Ignoring log offset '{}': {e}", path.display()))
+                .ok(),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!("Failed to read log offset '{}': {err}", path.display());
+                None
+            }
+        }
+    }
+
+    async fn save_offset(&self) {
+        let Some(path) = &self.offset_file else {
+            return;
+        };
+        let offset = Offset {
+            id: self.id,
+            // The unfinished line is read again after a restart
+            offset: self.read_to - self.partial.len() as u64,
+        };
+        let json = match serde_json::to_vec(&offset) {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Error converting log offset to json: {err}");
+                return;
+            }
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.ok();
+        }
+        let tmp = path.with_extension("tmp");
+        let result = match fs::write(&tmp, json).await {
+            Ok(()) => fs::rename(&tmp, path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!("Failed to write log offset '{}': {err}", path.display());
+        }
+    }
+}
+
+fn take_line(buf: &mut Vec<u8>) -> String {
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    let line = String::from_utf8_lossy(buf).into_owned();
+    buf.clear();
+    line
+}
+
+#[cfg(unix)]
+fn file_id(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(meta: &std::fs::Metadata) -> u64 {
+    meta.created()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+fn default_poll() -> u64 {
+    500
+}
+
+fn default_commands() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn append(path: &PathBuf, text: &str) {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .unwrap();
+        file.write_all(text.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follow_rotate_and_resume() {
+        let dir = std::env::temp_dir().join(format!("bridge-tail-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let log = dir.join("server.log");
+        let offset = dir.join("offset.json");
+        fs::write(&log, "old\n").await.unwrap();
+
+        let mut tail = LogTail::new(log.clone(), Some(offset.clone()), false);
+        assert!(tail.read_lines().await.unwrap().is_empty());
+        append(&log, "one\r\ntw").await;
+        assert_eq!(tail.read_lines().await.unwrap(), ["one"]);
+        append(&log, "o\n").await;
+        assert_eq!(tail.read_lines().await.unwrap(), ["two"]);
+
+        append(&log, "last\n").await;
+        fs::rename(&log, dir.join("server.log.1")).await.unwrap();
+        fs::write(&log, "new\n").await.unwrap();
+        assert_eq!(tail.read_lines().await.unwrap(), ["last", "new"]);
+
+        append(&log, "unread\n").await;
+        let mut restarted = LogTail::new(log.clone(), Some(offset), false);
+        assert_eq!(restarted.read_lines().await.unwrap(), ["unread"]);
+
+        fs::write(&log, "short\n").await.unwrap();
+        assert_eq!(restarted.read_lines().await.unwrap(), ["short"]);
+        fs::remove_dir_all(dir).await.ok();
+    }
+}
@@ -0,0 +1,454 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Cron fires checked before a task is considered to never run inside its window.
+const MAX_SKIPS: usize = 1000;
+/// Longest delay, offset, jitter or duration accepted from a config, 100 years.
+pub const MAX_SECONDS: u64 = 100 * 365 * 86400;
+
+/// When a scheduled task is allowed to run.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct TimingConfig {
+    /// IANA name like `Europe/Berlin`, the local time of the host if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// `2025-06-14`, `2025-06-14 18:00` or a time of day `18:00` for a daily window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    /// Same formats as `start`, a date includes the whole day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    /// Seconds before the first run
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64,
+    /// Up to this many random seconds added to every run
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub jitter: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Zone {
+    Local,
+    Tz(Tz),
+}
+
+impl Zone {
+    fn parse(name: Option<&str>) -> anyhow::Result<Self> {
+        match name {
+            None => Ok(Zone::Local),
+            Some(name) => name
+                .parse()
+                .map(Zone::Tz)
+                .map_err(|e| anyhow!("Invalid timezone \"{name}\": {e}")),
+        }
+    }
+
+    fn naive(self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Local => time.with_timezone(&Local).naive_local(),
+            Zone::Tz(tz) => time.with_timezone(&tz).naive_local(),
+        }
+    }
+
+    /// Wall clock time of this zone in UTC, times skipped by DST move forward an hour.
+    fn resolve(self, time: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let resolve = |time: NaiveDateTime| match self {
+            Zone::Local => Local
+                .from_local_datetime(&time)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc)),
+            Zone::Tz(tz) => tz
+                .from_local_datetime(&time)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc)),
+        };
+        resolve(time).or_else(|| resolve(time + TimeDelta::hours(1)))
+    }
+
+    fn next_cron(self, schedule: &cron::Schedule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Local => schedule
+                .after(&after.with_timezone(&Local))
+                .next()
+                .map(|t| t.with_timezone(&Utc)),
+            Zone::Tz(tz) => schedule
+                .after(&after.with_timezone(&tz))
+                .next()
+                .map(|t| t.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bound {
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    Time(NaiveTime),
+}
+
+impl Bound {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        for format in [
+            "%Y-%m-%d %H:%M:%S",
+            "%Y-%m-%d %H:%M",
+            "%Y-%m-%dT%H:%M:%S",
+            "%Y-%m-%dT%H:%M",
+        ] {
+            if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+                return Ok(Bound::DateTime(time));
+            }
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+            return Ok(Bound::Date(date));
+        }
+        for format in ["%H:%M:%S", "%H:%M"] {
+            if let Ok(time) = NaiveTime::parse_from_str(text, format) {
+                return Ok(Bound::Time(time));
+            }
+        }
+        Err(anyhow!(
+            "Invalid time \"{text}\", expected a date, a date with time or a time of day"
+        ))
+    }
+
+    /// Absolute wall clock time, `end` dates include the whole day.
+    fn absolute(self, end: bool) -> Option<NaiveDateTime> {
+        match self {
+            Bound::Date(date) if end => date.succ_opt().map(|d| d.and_time(NaiveTime::MIN)),
+            Bound::Date(date) => Some(date.and_time(NaiveTime::MIN)),
+            Bound::DateTime(time) => Some(time),
+            Bound::Time(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Window {
+    Always,
+    Period {
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    },
+    /// Every day, `start > end` wraps over midnight
+    Daily {
+        start: NaiveTime,
+        end: NaiveTime,
+    },
+}
+
+impl Window {
+    fn new(start: Option<&str>, end: Option<&str>, zone: Zone) -> anyhow::Result<Self> {
+        let start = start.map(Bound::parse).transpose()?;
+        let end = end.map(Bound::parse).transpose()?;
+        let daily = |bound: Option<Bound>| matches!(bound, Some(Bound::Time(_)));
+
+        match (start, end) {
+            (None, None) => Ok(Window::Always),
+            _ if daily(start) || daily(end) => match (start, end) {
+                (Some(Bound::Time(_)) | None, Some(Bound::Time(_)) | None) => {
+                    let time = |bound| match bound {
+                        Some(Bound::Time(time)) => time,
+                        _ => NaiveTime::MIN,
+                    };
+                    Ok(Window::Daily {
+                        start: time(start),
+                        end: time(end),
+                    })
+                }
+                _ => Err(anyhow!("`start` and `end` mix a time of day with a date")),
+            },
+            _ => {
+                let resolve = |bound: Option<Bound>, end: bool| {
+                    bound
+                        .and_then(|bound| bound.absolute(end))
+                        .and_then(|time| zone.resolve(time))
+                };
+                Ok(Window::Period {
+                    start: resolve(start, false),
+                    end: resolve(end, true),
+                })
+            }
+        }
+    }
+
+    fn contains(&self, time: DateTime<Utc>, zone: Zone) -> bool {
+        match self {
+            Window::Always => true,
+            Window::Period { start, end } => {
+                start.is_none_or(|start| time >= start) && end.is_none_or(|end| time < end)
+            }
+            Window::Daily { start, end } => {
+                let now = zone.naive(time).time();
+                if start < end {
+                    *start <= now && now < *end
+                } else {
+                    now >= *start || now < *end
+                }
+            }
+        }
+    }
+
+    /// The first moment at or after `time` inside the window, `None` once it closed for good.
+    fn next_open(&self, time: DateTime<Utc>, zone: Zone) -> Option<DateTime<Utc>> {
+        if self.contains(time, zone) {
+            return Some(time);
+        }
+        match self {
+            Window::Always => Some(time),
+            Window::Period { start, end } => {
+                if end.is_some_and(|end| time >= end) {
+                    None
+                } else {
+                    *start
+                }
+            }
+            Window::Daily { start, .. } => {
+                let now = zone.naive(time);
+                let mut open = now.date().and_time(*start);
+                if open <= now {
+                    open += TimeDelta::days(1);
+                }
+                zone.resolve(open)
+            }
+        }
+    }
+}
+
+/// Compiled [`TimingConfig`].
+#[derive(Debug, Clone)]
+pub struct Timing {
+    zone: Zone,
+    window: Window,
+    offset: TimeDelta,
+    jitter: TimeDelta,
+}
+
+impl Timing {
+    pub fn new(config: &TimingConfig) -> anyhow::Result<Self> {
+        let zone = Zone::parse(config.timezone.as_deref())?;
+        Ok(Self {
+            zone,
+            window: Window::new(config.start.as_deref(), config.end.as_deref(), zone)?,
+            offset: config_seconds(config.offset, "offset")?,
+            jitter: config_seconds(config.jitter, "jitter")?,
+        })
+    }
+
+    /// Absolute time like `2025-06-14 18:00` in the timezone of the task.
+    pub fn parse_time(&self, text: &str) -> anyhow::Result<DateTime<Utc>> {
+        Bound::parse(text)?
+            .absolute(false)
+            .and_then(|time| self.zone.resolve(time))
+            .ok_or_else(|| anyhow!("\"{text}\" is not an absolute time"))
+    }
+
+    /// Earliest time of the first run for a task started at `now`.
+    pub fn first_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + self.offset
+    }
+
+    /// Next cron fire after `after` that falls inside the window.
+    pub fn next_cron(
+        &self,
+        schedule: &cron::Schedule,
+        after: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let mut after = after;
+        for _ in 0..MAX_SKIPS {
+            let open = self.window.next_open(after, self.zone)?;
+            // `after` is exclusive, a fire exactly at the opening still counts
+            let from = if open > after {
+                open - TimeDelta::seconds(1)
+            } else {
+                after
+            };
+            let next = self.zone.next_cron(schedule, from)?;
+            if self.window.contains(next, self.zone) {
+                return Some(next);
+            }
+            after = next;
+        }
+        None
+    }
+
+    /// Next run at or after `at` inside the window.
+    pub fn next_run(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.window.next_open(at, self.zone)
+    }
+
+    pub fn jitter(&self) -> TimeDelta {
+        if self.jitter.is_zero() {
+            return TimeDelta::zero();
+        }
+        let max = self.jitter.num_milliseconds();
+        TimeDelta::milliseconds(rand::thread_rng().gen_range(0..=max))
+    }
+}
+
+/// Seconds from a config, values above [`MAX_SECONDS`] are rejected.
+pub fn config_seconds(value: u64, name: &str) -> anyhow::Result<TimeDelta> {
+    i64::try_from(value)
+        .ok()
+        .filter(|_| value <= MAX_SECONDS)
+        .and_then(TimeDelta::try_seconds)
+        .ok_or_else(|| anyhow!("`{name}` of {value} seconds is out of range"))
+}
+
+/// Parses durations like `10m`, `1h30m` or `45s`, a bare number is seconds.
+///
+/// `None` for invalid durations and ones longer than [`MAX_SECONDS`].
+pub fn parse_duration(text: &str) -> Option<TimeDelta> {
+    let max = TimeDelta::try_seconds(MAX_SECONDS as i64)?;
+    let text = text.trim();
+    // Bare seconds, without a sign like the units below
+    if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        let seconds = text.parse().ok()?;
+        return TimeDelta::try_seconds(seconds).filter(|d| *d <= max);
+    }
+    let mut total = TimeDelta::zero();
+    let mut number = String::new();
+    for ch in text.chars() {
+        if ch.is_ascii_digit() {
+            number.push(ch);
+            continue;
+        }
+        let value: i64 = number.parse().ok()?;
+        number.clear();
+        let part = match ch {
+            'd' => TimeDelta::try_days(value),
+            'h' => TimeDelta::try_hours(value),
+            'm' => TimeDelta::try_minutes(value),
+            's' => TimeDelta::try_seconds(value),
+            _ => return None,
+        }?;
+        total = total.checked_add(&part).filter(|total| *total <= max)?;
+    }
+    number.is_empty().then_some(total)
+}
+
+/// Human readable duration for announcements, e.g. `1 hour 5 minutes`.
+pub fn format_duration(duration: TimeDelta) -> String {
+    let seconds = duration.num_seconds().max(0);
+    let parts = [
+        (seconds / 86400, "day"),
+        (seconds % 86400 / 3600, "hour"),
+        (seconds % 3600 / 60, "minute"),
+        (seconds % 60, "second"),
+    ];
+    let text: Vec<String> = parts
+        .iter()
+        .filter(|(value, _)| *value != 0)
+        .map(|(value, unit)| match value {
+            1 => format!("1 {unit}"),
+            _ => format!("{value} {unit}s"),
+        })
+        .collect();
+    if text.is_empty() {
+        "0 seconds".to_string()
+    } else {
+        text.join(" ")
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn timing(yaml: &str) -> Timing {
+        Timing::new(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_timezone() {
+        let timing = timing("timezone: Europe/Berlin");
+        let schedule = cron::Schedule::from_str("0 0 20 * * *").unwrap();
+        assert_eq!(
+            timing.next_cron(&schedule, utc("2025-01-10T12:00:00Z")),
+            Some(utc("2025-01-10T19:00:00Z"))
+        );
+        assert!(Timing::new(&serde_yaml::from_str("timezone: Mars/Base").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_period() {
+        let timing = timing("{timezone: UTC, start: 2025-06-14, end: 2025-06-15}");
+        let schedule = cron::Schedule::from_str("0 0 12 * * *").unwrap();
+        let first = timing.next_cron(&schedule, utc("2025-06-01T00:00:00Z"));
+        assert_eq!(first, Some(utc("2025-06-14T12:00:00Z")));
+        let second = timing.next_cron(&schedule, first.unwrap());
+        assert_eq!(second, Some(utc("2025-06-15T12:00:00Z")));
+        assert_eq!(timing.next_cron(&schedule, second.unwrap()), None);
+        assert_eq!(timing.next_run(utc("2025-06-16T00:00:00Z")), None);
+    }
+
+    #[test]
+    fn test_daily() {
+        let timing = timing("{timezone: UTC, start: '22:00', end: '02:00'}");
+        assert_eq!(
+            timing.next_run(utc("2025-06-14T12:00:00Z")),
+            Some(utc("2025-06-14T22:00:00Z"))
+        );
+        let inside = utc("2025-06-15T01:00:00Z");
+        assert_eq!(timing.next_run(inside), Some(inside));
+        assert!(
+            Timing::new(&serde_yaml::from_str("{start: '22:00', end: 2025-06-15}").unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(parse_duration("10m"), Some(TimeDelta::minutes(10)));
+        assert_eq!(parse_duration("1h30m"), Some(TimeDelta::minutes(90)));
+        assert_eq!(parse_duration("45"), Some(TimeDelta::seconds(45)));
+        assert_eq!(parse_duration("-30"), None);
+        assert_eq!(parse_duration("+30"), None);
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10m5"), None);
+        assert_eq!(parse_duration("9223372036854775807d"), None);
+        assert_eq!(parse_duration("99999999999999s"), None);
+        assert_eq!(parse_duration("36500d1s"), None);
+        assert_eq!(format_duration(TimeDelta::minutes(61)), "1 hour 1 minute");
+        assert_eq!(format_duration(TimeDelta::seconds(10)), "10 seconds");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let timing = timing("timezone: Europe/Berlin");
+        assert_eq!(
+            timing.parse_time("2025-01-10 20:00").unwrap(),
+            utc("2025-01-10T19:00:00Z")
+        );
+        assert!(timing.parse_time("20:00").is_err());
+    }
+
+    #[test]
+    fn test_offset_jitter() {
+        let timing = timing("{offset: 30, jitter: 5}");
+        let now = utc("2025-06-14T12:00:00Z");
+        assert_eq!(timing.first_run(now), utc("2025-06-14T12:00:30Z"));
+        let jitter = timing.jitter();
+        assert!(jitter >= TimeDelta::zero() && jitter <= TimeDelta::seconds(5));
+
+        for yaml in [
+            "offset: 18446744073709551615",
+            "jitter: 9223372036854775807",
+        ] {
+            assert!(Timing::new(&serde_yaml::from_str(yaml).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,196 @@
+pub mod model;
+
+use crate::econ_mock::model::ConfigEconMock;
+use crate::format::formatting;
+use crate::model::{BaseConfig, CowStr};
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use serde_yaml::{Mapping, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{interval_at, sleep_until, Instant};
+
+pub async fn main(config_path: String) -> anyhow::Result<()> {
+    let config = ConfigEconMock::load_yaml(&config_path).await?;
+    config.set_logging();
+
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("Econ mock listening on {}", listener.local_addr()?);
+    serve(listener, config).await
+}
+
+/// Accepts clients forever, each one gets its own playback of the script.
+async fn serve(listener: TcpListener, config: ConfigEconMock<'static>) -> anyhow::Result<()> {
+    let mut script: Vec<CowStr<'static>> = config.script.lines.clone();
+    if let Some(file) = &config.script.file {
+        let contents = fs::read_to_string(file)
+            .await
+            .map_err(|e| anyhow!("Failed to read script file '{file}': {e}"))?;
+        script.extend(contents.lines().map(|line| CowStr::Owned(line.to_string())));
+    }
+    let script = Arc::new(script);
+    let config = Arc::new(config);
+
+    for cid in 0.. {
+        let (stream, addr) = listener.accept().await?;
+        info!("[{addr}] connected, cid={cid}");
+        let (config, script) = (config.clone(), script.clone());
+        tokio::spawn(async move {
+            match handle_client(stream, cid, &config, &script).await {
+                Ok(()) => info!("[{addr}] disconnected"),
+                Err(err) => warn!("[{addr}] {err}"),
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    cid: u64,
+    config: &ConfigEconMock<'_>,
+    script: &[CowStr<'_>],
+) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    write_line(&mut write, "Enter password:").await?;
+    let mut tries = 0;
+    loop {
+        let Some(password) = lines.next_line().await? else {
+            return Ok(());
+        };
+        if password.trim_end_matches('\r') == config.password {
+            break;
+        }
+        tries += 1;
+        if tries >= config.max_tries {
+            write_line(&mut write, "Too many authentication tries").await?;
+            return Ok(());
+        }
+        let message = format!("Wrong password {tries}/{}.", config.max_tries);
+        write_line(&mut write, &message).await?;
+    }
+    write_line(&mut write, &config.auth_message).await?;
+    debug!("cid={cid} authenticated");
+
+    let period = Duration::from_millis(config.script.interval.max(1));
+    let mut ticks = interval_at(Instant::now() + period, period);
+    let mut position = 0;
+    let disconnect_at = (config.disconnect.after != 0)
+        .then(|| Instant::now() + Duration::from_secs(config.disconnect.after));
+
+    loop {
+        let playing = position < script.len() || (config.script.repeat && !script.is_empty());
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(command) = line? else {
+                    return Ok(());
+                };
+                let command = command.trim_end_matches('\r');
+                debug!("cid={cid} command: {command}");
+                if config.disconnect.command.as_deref() == Some(command) {
+                    info!("cid={cid} simulated disconnect by command");
+                    return Ok(());
+                }
+                if let Some(echo) = &config.echo {
+                    write_line(&mut write, &render(echo, cid, &[command])).await?;
+                }
+            }
+            _ = ticks.tick(), if playing => {
+                let line = &script[position % script.len()];
+                position += 1;
+                write_line(&mut write, &render(line, cid, &[] as &[&str])).await?;
+            }
+            () = sleep_until(disconnect_at.unwrap_or_else(Instant::now)), if disconnect_at.is_some() => {
+                info!("cid={cid} simulated disconnect after {}s", config.disconnect.after);
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn render(template: &str, cid: u64, list: &[&str]) -> String {
+    let mut args = Mapping::new();
+    args.insert(
+        "now".into(),
+        chrono::Local::now()
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+            .into(),
+    );
+    args.insert("cid".into(), cid.into());
+    formatting::get_and_format(template, &Value::Mapping(args), list).into_owned()
+}
+
+async fn write_line(write: &mut OwnedWriteHalf, line: &str) -> std::io::Result<()> {
+    write.write_all(format!("{line}\n").as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_tw_econ::Econ;
+    use std::net::SocketAddr;
+    use tokio::time::sleep;
+
+    async fn start(yaml: &str) -> SocketAddr {
+        let config: ConfigEconMock = serde_yaml::from_str(yaml).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, config));
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, password: &str) -> (Econ, bool) {
+        let mut econ = Econ::new();
+        econ.connect(addr).await.unwrap();
+        let authed = econ.try_auth(password).await.unwrap();
+        (econ, authed)
+    }
+
+    /// Lines received within `wait`, oldest first.
+    async fn read_lines(econ: &mut Econ, wait: Duration) -> Vec<String> {
+        sleep(wait).await;
+        econ.fetch().await.ok();
+        let mut lines = Vec::new();
+        while let Some(line) = econ.pop_line() {
+            lines.insert(0, line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_auth_echo_and_script() {
+        let addr = start(
+            "{password: secret, echo: 'cmd={{0}} cid={{cid}}', \
+             script: {lines: [first, second], interval: 50}}",
+        )
+        .await;
+        assert!(!connect(addr, "wrong").await.1);
+
+        let (mut econ, authed) = connect(addr, "secret").await;
+        assert!(authed);
+        econ.send_line("say hi").await.unwrap();
+        let lines = read_lines(&mut econ, Duration::from_millis(300)).await;
+        assert!(lines.contains(&"cmd=say hi cid=1".to_string()));
+        let script: Vec<_> = lines.iter().filter(|l| !l.starts_with("cmd=")).collect();
+        assert_eq!(script, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let addr = start("{password: secret, disconnect: {command: drop}}").await;
+        let (mut econ, _) = connect(addr, "secret").await;
+        read_lines(&mut econ, Duration::ZERO).await;
+        econ.send_line("drop").await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        // A closed socket reads zero bytes instead of blocking
+        assert!(econ.fetch().await.is_ok());
+        assert!(econ.pop_line().is_none());
+    }
+}
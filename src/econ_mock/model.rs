@@ -0,0 +1,88 @@
+use crate::model::{BaseConfig, CowStr};
+use crate::nats::NatsConfig;
+use nestify::nest;
+use serde::Deserialize;
+
+nest! {
+    #[derive(Default, Clone, Deserialize)]
+    pub struct ConfigEconMock<'a> {
+        logging: Option<String>,
+        /// Not used by the mock, kept so the config loads like the other services
+        #[serde(default)]
+        pub nats: NatsConfig<'a>,
+
+        #[serde(default = "default_listen")]
+        pub listen: String,
+        pub password: String,
+        #[serde(default = "default_auth_message")]
+        pub auth_message: String,
+        /// Failed password attempts before the connection is closed
+        #[serde(default = "default_max_tries")]
+        pub max_tries: u32,
+        /// Line printed for every received command, {{0}} - command, `null` - silent
+        #[serde(default = "default_echo")]
+        pub echo: Option<CowStr<'static>>,
+        /// Lines sent to every authenticated client, {{now}} - current time, {{cid}} - client id
+        #[serde(default)]
+        pub script:
+            #[derive(Default, Clone, Deserialize)]
+            pub struct ScriptConfig {
+                #[serde(default)]
+                pub lines: Vec<CowStr<'static>>,
+                /// Recorded econ output, one line each, played after `lines`
+                #[serde(default)]
+                pub file: Option<String>,
+                /// Milliseconds between two lines
+                #[serde(default = "default_script_interval")]
+                pub interval: u64,
+                /// Start over after the last line
+                #[serde(default)]
+                pub repeat: bool,
+            },
+        #[serde(default)]
+        pub disconnect:
+            #[derive(Default, Clone, Deserialize)]
+            pub struct DisconnectConfig {
+                /// Seconds after authentication the connection is closed, 0 - never
+                #[serde(default)]
+                pub after: u64,
+                /// Command closing the connection when received
+                #[serde(default)]
+                pub command: Option<String>,
+            },
+    }
+}
+
+impl BaseConfig for ConfigEconMock<'_> {
+    fn nats_config(&self) -> &NatsConfig<'_> {
+        &self.nats
+    }
+
+    fn logging_config(&self) -> Option<String> {
+        self.logging.clone()
+    }
+
+    async fn default_config() -> &'static str {
+        include_str!("../default_config/econ_mock.yaml")
+    }
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:8303".to_string()
+}
+
+fn default_auth_message() -> String {
+    "Authentication successful. External console access granted.".to_string()
+}
+
+fn default_max_tries() -> u32 {
+    3
+}
+
+fn default_echo() -> Option<CowStr<'static>> {
+    Some(CowStr::Borrowed("{{now}} I econ: cid={{cid}} cmd='{{0}}'"))
+}
+
+fn default_script_interval() -> u64 {
+    1000
+}
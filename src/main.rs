@@ -12,11 +12,13 @@ use tokio::time::sleep;
 
 mod args;
 mod econ;
+mod econ_mock;
 mod errors;
 mod format;
 mod handler;
 mod model;
 mod nats;
+mod query;
 mod tg;
 mod util;
 mod value;
@@ -51,8 +53,12 @@ enum TgAction {
 enum Actions {
     #[command(about = "econ -> nats", visible_alias = "r")]
     Econ,
+    #[command(about = "Econ server emulator for local development")]
+    EconMock,
     #[command(about = "nats -> nats", visible_alias = "h")]
     Handler,
+    #[command(about = "server info -> nats", visible_alias = "q")]
+    Query,
     #[command(about = "Sending-receiving messages via telegram bots")]
     Tg {
         #[command(subcommand)]
@@ -79,7 +85,9 @@ async fn main() -> anyhow::Result<()> {
 
     match &cli.action {
         Actions::Econ => econ::main(cli.config).await,
+        Actions::EconMock => econ_mock::main(cli.config).await,
         Actions::Handler => handler::main(cli.config).await,
+        Actions::Query => query::main(cli.config).await,
         Actions::Tg { action } => match action {
             TgAction::Writer => tg::writer::main(cli.config).await,
             TgAction::Reader => tg::reader::main(cli.config).await,
@@ -1,8 +1,6 @@
 use crate::errors::ConfigError;
 use crate::nats::{Nats, NatsAuth, NatsConfig};
-use anyhow::anyhow;
 use async_nats::ConnectOptions;
-use async_tw_econ::Econ;
 use env_logger::Builder;
 use log::{debug, LevelFilter};
 use serde::de::DeserializeOwned;
@@ -97,9 +95,6 @@ pub trait BaseConfig: DeserializeOwned + Sized {
     async fn default_config() -> &'static str {
         ""
     }
-    async fn econ_connect(&self) -> anyhow::Result<Econ> {
-        Err(anyhow!("NOT IMPLEMENTED"))
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,5 +1,6 @@
 use crate::model::CowStr;
 use anyhow::anyhow;
+use async_nats::jetstream::kv::{self, Store};
 use async_nats::jetstream::publish::PublishAck;
 use async_nats::jetstream::Context;
 use async_nats::subject::ToSubject;
@@ -57,6 +58,20 @@ impl Nats {
         }
     }
 
+    /// Opens a key-value bucket, creating it on first use.
+    pub async fn key_value(&self, bucket: &str) -> anyhow::Result<Store> {
+        if let Ok(store) = self.js.get_key_value(bucket).await {
+            return Ok(store);
+        }
+        self.js
+            .create_key_value(kv::Config {
+                bucket: bucket.to_string(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to open key-value bucket \"{bucket}\": {e}"))
+    }
+
     pub async fn publish_bytes(
         &self,
         patch: CowStr<'_>,
@@ -78,6 +93,35 @@ impl Nats {
     }
 }
 
+/// Matches a subject against a pattern with NATS wildcards (`*` - one token, `>` - the rest).
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(part)) if token == part => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
 fn default_ping_interval() -> u64 {
     15
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject_matches() {
+        assert!(subject_matches("tw.econ.write.*", "tw.econ.write.1"));
+        assert!(!subject_matches("tw.econ.write.*", "tw.econ.write.1.2"));
+        assert!(subject_matches("tw.econ.>", "tw.econ.write.1.2"));
+        assert!(!subject_matches("tw.econ.>", "tw.econ"));
+        assert!(subject_matches("tw.sync", "tw.sync"));
+        assert!(!subject_matches("tw.sync", "tw.sync.x"));
+    }
+}
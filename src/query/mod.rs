@@ -0,0 +1,210 @@
+pub mod model;
+mod protocol;
+
+use crate::econ::roster::{Player, RosterSnapshot};
+use crate::format_values;
+use crate::model::{BaseConfig, CowStr};
+use crate::nats::Nats;
+use crate::query::model::{ConfigQuery, QueryServer};
+use crate::query::protocol::{query, ServerInfo};
+use anyhow::{anyhow, Context};
+use async_nats::jetstream::kv::Store;
+use bytes::Bytes;
+use futures_util::future::join_all;
+use log::{debug, info, trace, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Roster of a queried server together with what the server info tells about it.
+#[derive(Debug, Serialize)]
+struct QuerySnapshot<'a> {
+    #[serde(flatten)]
+    roster: RosterSnapshot<'a>,
+    online: bool,
+    #[serde(flatten)]
+    info: Option<&'a ServerInfo>,
+}
+
+struct QueryOutput {
+    server: String,
+    subject: Option<CowStr<'static>>,
+    store: Option<(Store, String)>,
+    timeout: Duration,
+}
+
+pub async fn main(config_path: String) -> anyhow::Result<()> {
+    let config = ConfigQuery::load_yaml(&config_path).await?;
+    config.set_logging();
+
+    let servers = config.servers();
+    if servers.is_empty() {
+        return Err(anyhow!("No servers to query configured"));
+    }
+    let nats = config.connect_nats().await?;
+    let bucket = match &config.kv_bucket {
+        Some(bucket) => Some(nats.key_value(bucket).await?),
+        None => None,
+    };
+
+    let handles: Vec<_> = servers
+        .into_iter()
+        .map(|server| {
+            let args = server.args.clone().unwrap_or_default();
+            let output = QueryOutput {
+                server: server.name(),
+                subject: config
+                    .subject
+                    .as_ref()
+                    .map(|subject| format_values!(subject, &args, &[] as &[&str]; single)),
+                store: bucket.clone().map(|store| {
+                    let key = format_values!(config.kv_key, &args, &[] as &[&str]; single);
+                    (store, key.into_owned())
+                }),
+                timeout: Duration::from_millis(config.timeout),
+            };
+            tokio::spawn(poll_server(nats.clone(), server, output))
+        })
+        .collect();
+    join_all(handles).await;
+
+    Ok(())
+}
+
+/// Queries one server forever, publishing a snapshot after every attempt.
+async fn poll_server(nats: Nats, server: QueryServer, output: QueryOutput) {
+    let name = &output.server;
+    info!(
+        "[{name}] querying {} every {}s",
+        server.address,
+        server.interval.unwrap_or_default()
+    );
+    // Join times are only known from the first query a player showed up in
+    let mut joined: HashMap<String, i64> = HashMap::new();
+    let mut online = None;
+    let mut ticks = interval(Duration::from_secs(
+        server.interval.unwrap_or_default().max(1),
+    ));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        let info = match fetch(&server, output.timeout).await {
+            Ok(info) => Some(info),
+            Err(err) => {
+                if online != Some(false) {
+                    warn!("[{name}] server info query failed: {err}");
+                }
+                None
+            }
+        };
+        if online != Some(info.is_some()) {
+            debug!("[{name}] online: {}", info.is_some());
+            online = Some(info.is_some());
+        }
+
+        let players = info
+            .as_ref()
+            .map(|info| players(info, &mut joined))
+            .unwrap_or_default();
+        let snapshot = QuerySnapshot {
+            roster: RosterSnapshot {
+                server: name,
+                updated_at: chrono::Utc::now().timestamp(),
+                players,
+            },
+            online: info.is_some(),
+            info: info.as_ref(),
+        };
+        publish_snapshot(&nats, &output, &snapshot).await;
+    }
+}
+
+async fn fetch(server: &QueryServer, timeout: Duration) -> anyhow::Result<ServerInfo> {
+    let addr = lookup_host(&server.address)
+        .await?
+        .next()
+        .with_context(|| format!("No socket address resolved from '{}'", server.address))?;
+    query(addr, server.protocol, timeout).await
+}
+
+/// Clients of the reply as roster players, spectators are in team -1.
+fn players(info: &ServerInfo, joined: &mut HashMap<String, i64>) -> Vec<Player> {
+    let now = chrono::Utc::now().timestamp();
+    joined.retain(|name, _| info.clients.iter().any(|client| &client.name == name));
+    info.clients
+        .iter()
+        .enumerate()
+        .map(|(index, client)| Player {
+            client_id: index as i32,
+            name: client.name.clone(),
+            clan: Some(client.clan.clone()).filter(|clan| !clan.is_empty()),
+            team: Some(if client.is_player { 0 } else { -1 }),
+            joined_at: *joined.entry(client.name.clone()).or_insert(now),
+        })
+        .collect()
+}
+
+async fn publish_snapshot(nats: &Nats, output: &QueryOutput, snapshot: &QuerySnapshot<'_>) {
+    let json = match serde_json::to_vec_pretty(snapshot) {
+        Ok(json) => Bytes::from(json),
+        Err(err) => {
+            warn!("Error converting server info to json: {err}");
+            return;
+        }
+    };
+    if let Some(subject) = &output.subject {
+        trace!("Sending server info to {subject}");
+        nats.publish_bytes(subject.clone(), json.clone()).await.ok();
+    }
+    if let Some((store, key)) = &output.store {
+        if let Err(err) = store.put(key.as_str(), json).await {
+            warn!("Failed to write server info \"{key}\": {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::protocol::ClientInfo;
+
+    fn client(name: &str, is_player: bool) -> ClientInfo {
+        ClientInfo {
+            name: name.to_string(),
+            clan: String::new(),
+            country: -1,
+            score: 0,
+            is_player,
+        }
+    }
+
+    #[test]
+    fn test_players() {
+        let mut joined = HashMap::from([("left".to_string(), 1), ("stays".to_string(), 2)]);
+        let info = ServerInfo {
+            clients: vec![client("stays", true), client("new", false)],
+            ..Default::default()
+        };
+        let players = players(&info, &mut joined);
+        assert_eq!(players[0].joined_at, 2);
+        assert_eq!(players[1].team, Some(-1));
+        assert_eq!(players[1].clan, None);
+        assert!(!joined.contains_key("left"));
+
+        let json = serde_json::to_value(QuerySnapshot {
+            roster: RosterSnapshot {
+                server: "test",
+                updated_at: 0,
+                players,
+            },
+            online: true,
+            info: Some(&info),
+        })
+        .unwrap();
+        assert_eq!(json["players"][1]["name"], "new");
+        assert_eq!(json["server"], "test");
+        assert_eq!(json["max_clients"], 0);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::args::Args;
+use crate::model::{BaseConfig, CowStr};
+use crate::nats::NatsConfig;
+use crate::query::protocol::Protocol;
+use nestify::nest;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+nest! {
+    #[derive(Default, Clone, Deserialize)]
+    pub struct ConfigQuery<'a> {
+        logging: Option<String>,
+        pub nats: NatsConfig<'a>,
+
+        /// Seconds between two queries of a server
+        #[serde(default = "default_interval")]
+        pub interval: u64,
+        /// Milliseconds to wait for the whole reply
+        #[serde(default = "default_timeout")]
+        pub timeout: u64,
+        /// Subject for snapshots, published after every query
+        #[serde(default = "default_subject")]
+        pub subject: Option<CowStr<'static>>,
+        /// Key-value bucket keeping the last snapshot of every server
+        #[serde(default)]
+        pub kv_bucket: Option<String>,
+        #[serde(default = "default_kv_key")]
+        pub kv_key: CowStr<'static>,
+
+        pub servers: Vec<
+            #[derive(Default, Clone, Deserialize)]
+            pub struct QueryServer {
+                pub address: String,
+                #[serde(default)]
+                pub protocol: Protocol,
+                /// Overrides the global `interval`
+                #[serde(default)]
+                pub interval: Option<u64>,
+                pub args: Option<Value>,
+            }>,
+
+        pub args: Option<Value>,
+    }
+}
+
+impl ConfigQuery<'_> {
+    /// Servers with the global `args` merged into their own.
+    pub fn servers(&self) -> Vec<QueryServer> {
+        let global = self.args.clone().unwrap_or_default();
+        self.servers
+            .iter()
+            .map(|server| QueryServer {
+                interval: server.interval.or(Some(self.interval)),
+                args: Some(Args::merge_yaml_values(
+                    &global,
+                    &server.args.clone().unwrap_or_default(),
+                )),
+                ..server.clone()
+            })
+            .collect()
+    }
+}
+
+impl QueryServer {
+    /// Name used in logs and snapshots: `args.server_name` or the address
+    pub fn name(&self) -> String {
+        let args = self.args.clone().unwrap_or_default();
+        let name = Args::get(&args, "server_name", String::new());
+        if name.is_empty() {
+            self.address.clone()
+        } else {
+            name
+        }
+    }
+}
+
+impl BaseConfig for ConfigQuery<'_> {
+    fn nats_config(&self) -> &NatsConfig<'_> {
+        &self.nats
+    }
+
+    fn logging_config(&self) -> Option<String> {
+        self.logging.clone()
+    }
+
+    async fn default_config() -> &'static str {
+        include_str!("../default_config/query.yaml")
+    }
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+fn default_timeout() -> u64 {
+    3000
+}
+
+fn default_subject() -> Option<CowStr<'static>> {
+    Some(CowStr::Borrowed("tw.query.{{server_name}}"))
+}
+
+fn default_kv_key() -> CowStr<'static> {
+    CowStr::Borrowed("{{server_name}}")
+}
@@ -0,0 +1,538 @@
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+const GETINFO: &[u8] = b"\xff\xff\xff\xffgie3";
+const INFO: &[u8] = b"\xff\xff\xff\xffinf3";
+const INFO_EXTENDED: &[u8] = b"\xff\xff\xff\xffiext";
+const INFO_EXTENDED_MORE: &[u8] = b"\xff\xff\xff\xffiex+";
+
+/// Header of a 0.6 connless packet
+const HEADER_06: [u8; 6] = [0xff; 6];
+/// 0.7 packet flags, stored in the upper bits of the first header byte
+const FLAG_CONTROL_07: u8 = 1;
+const FLAG_CONNLESS_07: u8 = 8;
+const CTRL_TOKEN_07: u8 = 5;
+const TOKEN_NONE_07: [u8; 4] = [0xff; 4];
+/// Token requests are padded so they can't be used for amplification
+const TOKEN_REQUEST_SIZE_07: usize = 512;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Protocol {
+    /// 0.6 request with the extended token, answered with `iext`/`iex+`
+    #[default]
+    #[serde(rename = "ddnet")]
+    Ddnet,
+    #[serde(rename = "0.6")]
+    V06,
+    #[serde(rename = "0.7")]
+    V07,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub name: String,
+    pub map: String,
+    pub game_type: String,
+    pub passworded: bool,
+    pub num_players: i32,
+    pub max_players: i32,
+    pub num_clients: i32,
+    pub max_clients: i32,
+    #[serde(skip)]
+    pub clients: Vec<ClientInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub clan: String,
+    pub country: i32,
+    pub score: i32,
+    /// `false` for spectators
+    pub is_player: bool,
+}
+
+/// Asks `addr` for its server info, waiting up to `timeout` for the whole reply.
+pub async fn query(
+    addr: SocketAddr,
+    protocol: Protocol,
+    timeout: Duration,
+) -> anyhow::Result<ServerInfo> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    let deadline = Instant::now() + timeout;
+    // DDNet tokens are 24 bits wide, 8 of them in the request itself
+    let token = rand::random::<u32>() & 0x00ff_ffff;
+
+    match protocol {
+        Protocol::V06 => query_06(&socket, token & 0xff, deadline).await,
+        Protocol::Ddnet => query_ddnet(&socket, token, deadline).await,
+        Protocol::V07 => query_07(&socket, token, deadline).await,
+    }
+}
+
+async fn recv<'a>(
+    socket: &UdpSocket,
+    buf: &'a mut [u8],
+    deadline: Instant,
+) -> anyhow::Result<&'a [u8]> {
+    let len = timeout_at(deadline, socket.recv(buf))
+        .await
+        .map_err(|_| anyhow!("no reply in time"))??;
+    Ok(&buf[..len])
+}
+
+async fn query_06(socket: &UdpSocket, token: u32, deadline: Instant) -> anyhow::Result<ServerInfo> {
+    socket.send(&request_06(&HEADER_06, token)).await?;
+    let mut buf = [0; 2048];
+    loop {
+        let packet = recv(socket, &mut buf, deadline).await?;
+        if let Some(data) = packet
+            .get(HEADER_06.len()..)
+            .and_then(|data| data.strip_prefix(INFO))
+        {
+            let mut reader = Reader::new(data);
+            if reader.decimal()? as u32 == token {
+                return parse_info_06(&mut reader);
+            }
+        }
+    }
+}
+
+async fn query_ddnet(
+    socket: &UdpSocket,
+    token: u32,
+    deadline: Instant,
+) -> anyhow::Result<ServerInfo> {
+    let extra = (token >> 8) as u16;
+    let [extra_hi, extra_lo] = extra.to_be_bytes();
+    socket
+        .send(&request_06(
+            &[b'x', b'e', extra_hi, extra_lo, 0, 0],
+            token & 0xff,
+        ))
+        .await?;
+
+    let mut buf = [0; 2048];
+    let mut info: Option<ServerInfo> = None;
+    // `iex+` packets may arrive before the main one
+    let mut more = Vec::new();
+    loop {
+        let packet = match recv(socket, &mut buf, deadline).await {
+            Ok(packet) => packet,
+            // Lost `iex+` packets leave a partial list rather than nothing
+            Err(_) if info.is_some() => break,
+            Err(err) => return Err(err),
+        };
+        let Some(data) = packet.get(HEADER_06.len()..) else {
+            continue;
+        };
+        if let Some(data) = data.strip_prefix(INFO_EXTENDED) {
+            let mut reader = Reader::new(data);
+            if reader.decimal()? as u32 == token {
+                info = Some(parse_info_extended(&mut reader)?);
+            }
+        } else if let Some(data) = data.strip_prefix(INFO_EXTENDED_MORE) {
+            let mut reader = Reader::new(data);
+            if reader.decimal()? as u32 == token {
+                reader.decimal()?; // packet number
+                reader.string()?; // reserved
+                more.extend(parse_clients_extended(&mut reader)?);
+            }
+        }
+
+        if let Some(info) = &mut info {
+            info.clients.append(&mut more);
+            if info.clients.len() >= info.num_clients as usize {
+                break;
+            }
+        }
+    }
+    info.context("no reply in time")
+}
+
+async fn query_07(socket: &UdpSocket, token: u32, deadline: Instant) -> anyhow::Result<ServerInfo> {
+    let own_token = token.to_be_bytes();
+    let mut request = vec![FLAG_CONTROL_07 << 2, 0, 0];
+    request.extend_from_slice(&TOKEN_NONE_07);
+    request.push(CTRL_TOKEN_07);
+    request.extend_from_slice(&own_token);
+    request.resize(7 + TOKEN_REQUEST_SIZE_07, 0);
+    socket.send(&request).await?;
+
+    let mut buf = [0; 2048];
+    let server_token = loop {
+        let packet = recv(socket, &mut buf, deadline).await?;
+        if packet.len() >= 12
+            && (packet[0] >> 2) & FLAG_CONTROL_07 != 0
+            && packet[3..7] == own_token
+            && packet[7] == CTRL_TOKEN_07
+        {
+            break [packet[8], packet[9], packet[10], packet[11]];
+        }
+    };
+
+    let mut request = vec![(FLAG_CONNLESS_07 << 2) | 1];
+    request.extend_from_slice(&server_token);
+    request.extend_from_slice(&own_token);
+    request.extend_from_slice(GETINFO);
+    pack_int(&mut request, token as i32);
+    socket.send(&request).await?;
+
+    loop {
+        let packet = recv(socket, &mut buf, deadline).await?;
+        if packet.len() < 9 || (packet[0] >> 2) & FLAG_CONNLESS_07 == 0 || packet[1..5] != own_token
+        {
+            continue;
+        }
+        if let Some(data) = packet[9..].strip_prefix(INFO) {
+            let mut reader = Reader::new(data);
+            if reader.int()? as u32 == token {
+                return parse_info_07(&mut reader);
+            }
+        }
+    }
+}
+
+fn request_06(header: &[u8], token: u32) -> Vec<u8> {
+    let mut request = header.to_vec();
+    request.extend_from_slice(GETINFO);
+    request.push(token as u8);
+    request
+}
+
+fn parse_info_06(reader: &mut Reader) -> anyhow::Result<ServerInfo> {
+    let mut info = ServerInfo {
+        version: reader.string()?,
+        name: reader.string()?,
+        map: reader.string()?,
+        game_type: reader.string()?,
+        passworded: reader.decimal()? & 1 != 0,
+        num_players: reader.decimal()?,
+        max_players: reader.decimal()?,
+        num_clients: reader.decimal()?,
+        max_clients: reader.decimal()?,
+        clients: Vec::new(),
+    };
+    while !reader.is_empty() && info.clients.len() < info.num_clients as usize {
+        info.clients.push(ClientInfo {
+            name: reader.string()?,
+            clan: reader.string()?,
+            country: reader.decimal()?,
+            score: reader.decimal()?,
+            is_player: reader.decimal()? != 0,
+        });
+    }
+    Ok(info)
+}
+
+fn parse_info_extended(reader: &mut Reader) -> anyhow::Result<ServerInfo> {
+    let version = reader.string()?;
+    let name = reader.string()?;
+    let map = reader.string()?;
+    reader.decimal()?; // map crc
+    reader.decimal()?; // map size
+    let mut info = ServerInfo {
+        version,
+        name,
+        map,
+        game_type: reader.string()?,
+        passworded: reader.decimal()? & 1 != 0,
+        num_players: reader.decimal()?,
+        max_players: reader.decimal()?,
+        num_clients: reader.decimal()?,
+        max_clients: reader.decimal()?,
+        clients: Vec::new(),
+    };
+    reader.string()?; // reserved
+    info.clients = parse_clients_extended(reader)?;
+    Ok(info)
+}
+
+fn parse_clients_extended(reader: &mut Reader) -> anyhow::Result<Vec<ClientInfo>> {
+    let mut clients = Vec::new();
+    while !reader.is_empty() {
+        clients.push(ClientInfo {
+            name: reader.string()?,
+            clan: reader.string()?,
+            country: reader.decimal()?,
+            score: reader.decimal()?,
+            is_player: reader.decimal()? != 0,
+        });
+        reader.string()?; // reserved
+    }
+    Ok(clients)
+}
+
+fn parse_info_07(reader: &mut Reader) -> anyhow::Result<ServerInfo> {
+    let version = reader.string()?;
+    let name = reader.string()?;
+    reader.string()?; // hostname
+    let map = reader.string()?;
+    let game_type = reader.string()?;
+    let passworded = reader.int()? & 1 != 0;
+    reader.int()?; // skill level
+    let mut info = ServerInfo {
+        version,
+        name,
+        map,
+        game_type,
+        passworded,
+        num_players: reader.int()?,
+        max_players: reader.int()?,
+        num_clients: reader.int()?,
+        max_clients: reader.int()?,
+        clients: Vec::new(),
+    };
+    while !reader.is_empty() && info.clients.len() < info.num_clients as usize {
+        info.clients.push(ClientInfo {
+            name: reader.string()?,
+            clan: reader.string()?,
+            country: reader.int()?,
+            score: reader.int()?,
+            // bit 0 - spectator, bit 1 - bot
+            is_player: reader.int()? & 1 == 0,
+        });
+    }
+    Ok(info)
+}
+
+/// Appends `value` in the variable length encoding of 0.7.
+fn pack_int(buf: &mut Vec<u8>, value: i32) {
+    let mut first = if value < 0 { 0x40 } else { 0 };
+    let mut rest = (if value < 0 { !value } else { value }) as u32;
+    first |= (rest & 0x3f) as u8;
+    rest >>= 6;
+    while rest != 0 {
+        buf.push(first | 0x80);
+        first = (rest & 0x7f) as u8;
+        rest >>= 7;
+    }
+    buf.push(first);
+}
+
+/// Zero terminated fields of a server info reply.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let end = self
+            .data
+            .iter()
+            .position(|&byte| byte == 0)
+            .context("truncated server info")?;
+        let value = String::from_utf8_lossy(&self.data[..end]).into_owned();
+        self.data = &self.data[end + 1..];
+        Ok(value)
+    }
+
+    /// Integer written as a decimal string (0.6 and DDNet).
+    fn decimal(&mut self) -> anyhow::Result<i32> {
+        let value = self.string()?;
+        value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid number in server info: {value:?}"))
+    }
+
+    /// Variable length integer (0.7).
+    fn int(&mut self) -> anyhow::Result<i32> {
+        let Some((&first, mut rest)) = self.data.split_first() else {
+            bail!("truncated server info");
+        };
+        let negative = first & 0x40 != 0;
+        let mut value = u32::from(first & 0x3f);
+        let mut byte = first;
+        let mut shift = 6;
+        while byte & 0x80 != 0 {
+            let Some((&next, tail)) = rest.split_first() else {
+                bail!("truncated server info");
+            };
+            if shift > 27 {
+                bail!("invalid number in server info");
+            }
+            byte = next;
+            rest = tail;
+            value |= u32::from(byte & 0x7f) << shift;
+            shift += 7;
+        }
+        self.data = rest;
+        Ok(if negative {
+            !(value as i32)
+        } else {
+            value as i32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(values: &[&str]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.bytes().chain([0]))
+            .collect()
+    }
+
+    /// Answers like a DDNet server speaking all three protocols.
+    async fn stand_in() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                for reply in replies(&buf[..len]) {
+                    socket.send_to(&reply, from).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    fn replies(packet: &[u8]) -> Vec<Vec<u8>> {
+        let player = ["nameless", "clan", "276", "42", "1"];
+        if packet[0] == FLAG_CONTROL_07 << 2 {
+            let mut reply = vec![FLAG_CONTROL_07 << 2, 0, 0];
+            reply.extend_from_slice(&packet[8..12]);
+            reply.push(CTRL_TOKEN_07);
+            reply.extend_from_slice(&[1, 2, 3, 4]);
+            return vec![reply];
+        }
+        if packet[0] == (FLAG_CONNLESS_07 << 2) | 1 {
+            assert_eq!(packet[1..5], [1, 2, 3, 4]);
+            let mut reply = vec![packet[0]];
+            reply.extend_from_slice(&packet[5..9]);
+            reply.extend_from_slice(&[1, 2, 3, 4]);
+            reply.extend_from_slice(INFO);
+            let token = Reader::new(&packet[9 + GETINFO.len()..]).int().unwrap();
+            pack_int(&mut reply, token);
+            reply.extend(fields(&["0.7.5", "Vanilla", "", "ctf5", "CTF"]));
+            for value in [1, -1, 1, 16, 2, 16] {
+                pack_int(&mut reply, value);
+            }
+            for (name, flags) in [("red", 0), ("watcher", 1)] {
+                reply.extend(fields(&[name, ""]));
+                for value in [-1, 300, flags] {
+                    pack_int(&mut reply, value);
+                }
+            }
+            return vec![reply];
+        }
+
+        let basic = u32::from(packet[HEADER_06.len() + GETINFO.len()]);
+        if packet.starts_with(&HEADER_06) {
+            let mut reply = HEADER_06.to_vec();
+            reply.extend_from_slice(INFO);
+            let token = basic.to_string();
+            let head = [&token, "0.6.4", "Old", "dm1", "DM", "1", "1", "8", "1", "8"];
+            reply.extend(fields(&head));
+            reply.extend(fields(&player));
+            return vec![reply];
+        }
+
+        let token =
+            (u32::from(u16::from_be_bytes([packet[2], packet[3]])) << 8 | basic).to_string();
+        let mut main = HEADER_06.to_vec();
+        main.extend_from_slice(INFO_EXTENDED);
+        let head = [
+            &token,
+            "0.6.4, 19.0",
+            "DDNet",
+            "Tutorial",
+            "123",
+            "456",
+            "DDraceNetwork",
+            "0",
+            "2",
+            "64",
+            "3",
+            "64",
+            "",
+        ];
+        main.extend(fields(&head));
+        main.extend(fields(&player));
+        main.extend(fields(&[""]));
+        let mut more = HEADER_06.to_vec();
+        more.extend_from_slice(INFO_EXTENDED_MORE);
+        more.extend(fields(&[&token, "1", ""]));
+        more.extend(fields(&["brainless", "", "-1", "0", "1", ""]));
+        more.extend(fields(&["spec", "", "-1", "0", "0", ""]));
+        // The continuation overtakes the main packet
+        vec![more, main]
+    }
+
+    #[test]
+    fn test_pack_int() {
+        for value in [0, 1, 63, 64, -1, -64, -65, 1000, i32::MAX, i32::MIN] {
+            let mut buf = Vec::new();
+            pack_int(&mut buf, value);
+            let mut reader = Reader::new(&buf);
+            assert_eq!(reader.int().unwrap(), value);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = fields(&["0.6.4", "name"]);
+        assert!(parse_info_06(&mut Reader::new(&data)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let addr = stand_in().await;
+        let timeout = Duration::from_secs(2);
+
+        let info = query(addr, Protocol::V06, timeout).await.unwrap();
+        assert_eq!(info.map, "dm1");
+        assert!(info.passworded);
+        assert_eq!(info.clients[0].name, "nameless");
+        assert_eq!(info.clients[0].country, 276);
+
+        let info = query(addr, Protocol::Ddnet, timeout).await.unwrap();
+        assert_eq!(info.name, "DDNet");
+        assert_eq!(info.num_clients, 3);
+        let names: Vec<_> = info.clients.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["nameless", "brainless", "spec"]);
+        assert!(!info.clients[2].is_player);
+
+        let info = query(addr, Protocol::V07, timeout).await.unwrap();
+        assert_eq!(info.game_type, "CTF");
+        assert!(info.passworded);
+        assert_eq!(info.num_players, 1);
+        assert_eq!(info.clients[0].country, -1);
+        assert!(info.clients[0].is_player);
+        assert!(!info.clients[1].is_player);
+    }
+
+    #[tokio::test]
+    async fn test_no_reply() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let result = query(addr, Protocol::Ddnet, Duration::from_millis(100)).await;
+        assert!(result.is_err());
+    }
+}
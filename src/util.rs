@@ -1,6 +1,6 @@
 use crate::model::CowStr;
 use log::warn;
-use regex::Captures;
+use regex::{Captures, Regex};
 use std::borrow::Cow;
 
 pub fn convert<T>(payload: &[u8]) -> Option<T>
@@ -39,6 +39,12 @@ pub fn escape_string(cow: CowStr) -> CowStr {
     }
 }
 
+pub fn hardcoded_regex(pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|e| {
+        panic!("Hardcoded regex failed to compile: {e}");
+    })
+}
+
 pub fn captures_to_list<'a>(caps: &'a Captures<'a>) -> Vec<&'a str> {
     let mut out = Vec::with_capacity(caps.len());
     for cap in caps.iter().flatten() {